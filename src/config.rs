@@ -1,24 +1,99 @@
+use crate::network::codec::DEFAULT_MAX_FRAME_SIZE;
 use serde::{Deserialize, Serialize};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+
+/// What to do when a known username connects with a different identity key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KeyChangePolicy {
+    Refuse,
+    Warn,
+}
+
+/// How discovery announcements reach the rest of the LAN.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DiscoveryMode {
+    Broadcast, // subnet broadcast addresses
+    Multicast, // `multicast_group`, on every interface
+    Both,
+}
+
+impl DiscoveryMode {
+    pub fn broadcast(self) -> bool {
+        matches!(self, DiscoveryMode::Broadcast | DiscoveryMode::Both)
+    }
+
+    pub fn multicast(self) -> bool {
+        matches!(self, DiscoveryMode::Multicast | DiscoveryMode::Both)
+    }
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub discovery_port: u16,
+    pub discovery_mode: DiscoveryMode,
+    pub multicast_group: Ipv4Addr,
+    pub multicast_ttl: u32, // router hops multicast announcements may cross; 1 keeps them on the local link
+    pub ipv6: bool, // also discover over IPv6 link-local multicast and accept peers over IPv6
+    pub multicast_group_v6: Ipv6Addr, // link-local (ff02::/16) group for IPv6 announcements
+    pub mdns: bool, // also advertise and browse for a _localchat._tcp service over mDNS
+    pub include_interfaces: Vec<String>, // announce only on these interfaces (empty: all); "name*" matches a prefix
+    pub exclude_interfaces: Vec<String>, // never announce on these, e.g. "docker*"
     pub tcp_port_range: (u16, u16),
+    pub static_peers: Vec<String>, // "host:port" of peers to dial at startup, for networks discovery can't cover
     pub username: String,
     pub network_timeout: u64, // seconds
     pub heartbeat_interval: u64, // seconds
+    pub shutdown_timeout: u64, // seconds to wait for goodbyes before exiting anyway
     pub channel: Option<String>,
+    #[serde(skip)]
+    pub channel_passphrase: Option<String>,
+    pub max_frame_size: usize, // bytes
+    pub outbox_size: usize, // messages held per temporarily disconnected peer
+    pub outbox_ttl: u64, // seconds a held message (or a vanished peer) is kept
+    pub history_limit: usize, // recent chat messages kept for, and asked of, peers that join late
+    pub pex_interval: u64, // seconds between peer lists sent to each connection; 0 turns peer exchange off
+    pub relay: bool, // forward group messages between peers that can't reach each other
+    pub reconnect_delay: u64, // seconds before the first attempt to reach a lost peer again
+    pub reconnect_max_delay: u64, // seconds; the backoff between attempts stops growing here
+    pub config_dir: PathBuf,
+    pub identity_file: Option<PathBuf>, // overrides the per-username key under config_dir
+    pub key_change_policy: KeyChangePolicy,
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             discovery_port: 7878,
+            discovery_mode: DiscoveryMode::Broadcast,
+            multicast_group: Ipv4Addr::new(239, 255, 78, 78),
+            multicast_ttl: 1,
+            ipv6: true,
+            multicast_group_v6: Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0x7878),
+            mdns: false,
+            include_interfaces: Vec::new(),
+            exclude_interfaces: Vec::new(),
             tcp_port_range: (8000, 8100),
+            static_peers: Vec::new(),
             username: whoami::username(),
             network_timeout: 10,
-            heartbeat_interval: 30,
+            heartbeat_interval: 3,
+            shutdown_timeout: 3,
             channel: None,
+            channel_passphrase: None,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            outbox_size: 100,
+            outbox_ttl: 300,
+            history_limit: 50,
+            pex_interval: 30,
+            relay: false,
+            reconnect_delay: 1,
+            reconnect_max_delay: 30,
+            config_dir: dirs::config_dir()
+                .unwrap_or_else(|| PathBuf::from("."))
+                .join("local-chat"),
+            identity_file: None,
+            key_change_policy: KeyChangePolicy::Refuse,
         }
     }
 }
@@ -38,14 +113,70 @@ impl Config {
         self
     }
     
-    pub fn find_available_discovery_port(&self) -> u16 {
-        // Try the default port first, then try nearby ports
-        for port in self.discovery_port..self.discovery_port + 10 {
-            if let Ok(_) = std::net::UdpSocket::bind(("0.0.0.0", port)) {
-                return port;
-            }
+    pub fn with_channel_passphrase(mut self, passphrase: Option<String>) -> Self {
+        self.channel_passphrase = passphrase;
+        self
+    }
+    
+    pub fn with_identity_file(mut self, identity_file: Option<PathBuf>) -> Self {
+        self.identity_file = identity_file;
+        self
+    }
+    
+    pub fn with_key_change_policy(mut self, policy: KeyChangePolicy) -> Self {
+        self.key_change_policy = policy;
+        self
+    }
+    
+    pub fn with_discovery_mode(mut self, mode: DiscoveryMode) -> Self {
+        self.discovery_mode = mode;
+        self
+    }
+    
+    pub fn with_ipv6(mut self, ipv6: bool) -> Self {
+        self.ipv6 = ipv6;
+        self
+    }
+    
+    pub fn with_mdns(mut self, mdns: bool) -> Self {
+        self.mdns = mdns;
+        self
+    }
+    
+    pub fn with_static_peers(mut self, static_peers: Vec<String>) -> Self {
+        self.static_peers = static_peers;
+        self
+    }
+    
+    pub fn with_relay(mut self, relay: bool) -> Self {
+        self.relay = relay;
+        self
+    }
+    
+    /// Whether discovery may announce on the interface called `name`.
+    pub fn uses_interface(&self, name: &str) -> bool {
+        let matches = |pattern: &String| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        };
+        (self.include_interfaces.is_empty() || self.include_interfaces.iter().any(matches))
+            && !self.exclude_interfaces.iter().any(matches)
+    }
+    
+    pub fn known_peers_path(&self) -> PathBuf {
+        self.config_dir.join("known_peers")
+    }
+    
+    /// Where this node's identity key lives. Keys are kept per username so
+    /// several instances on one machine (see TESTING.md) get distinct ids.
+    pub fn identity_path(&self) -> PathBuf {
+        if let Some(path) = &self.identity_file {
+            return path.clone();
         }
-        // Fallback to any available port
-        0
+        let file_name: String = self.username
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+            .collect();
+        self.config_dir.join("identities").join(format!("{}.key", file_name))
     }
 }
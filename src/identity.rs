@@ -0,0 +1,142 @@
+use anyhow::{anyhow, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey, SECRET_KEY_LENGTH};
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use tracing::info;
+use uuid::Uuid;
+
+/// Long-lived Ed25519 keypair for this node. The peer id everyone else sees is
+/// derived from the public key, so it stays the same across restarts for as
+/// long as the key file is kept.
+pub struct Identity {
+    signing_key: SigningKey,
+    peer_id: Uuid,
+}
+
+impl Identity {
+    pub fn load_or_generate(path: &Path) -> Result<Self> {
+        let signing_key = if path.exists() {
+            let bytes = fs::read(path)
+                .with_context(|| format!("Failed to read identity key {}", path.display()))?;
+            let secret: [u8; SECRET_KEY_LENGTH] = bytes.as_slice().try_into()
+                .map_err(|_| anyhow!("Identity key {} is corrupt ({} bytes)", path.display(), bytes.len()))?;
+            info!("Loaded identity key from {}", path.display());
+            SigningKey::from_bytes(&secret)
+        } else {
+            let signing_key = SigningKey::generate(&mut OsRng);
+            Self::write_key(path, &signing_key)?;
+            info!("Generated new identity key at {}", path.display());
+            signing_key
+        };
+
+        let peer_id = peer_id_from_public_key(&signing_key.verifying_key());
+        Ok(Self { signing_key, peer_id })
+    }
+
+    /// A throwaway identity that is never written to disk.
+    #[cfg(test)]
+    pub fn ephemeral() -> Self {
+        let signing_key = SigningKey::generate(&mut OsRng);
+        let peer_id = peer_id_from_public_key(&signing_key.verifying_key());
+        Self { signing_key, peer_id }
+    }
+
+    pub fn peer_id(&self) -> Uuid {
+        self.peer_id
+    }
+
+    pub fn public_key(&self) -> String {
+        encode_public_key(&self.signing_key.verifying_key())
+    }
+
+    /// Signs `data` with the identity key, returning a base64 signature.
+    pub fn sign(&self, data: &[u8]) -> String {
+        BASE64.encode(self.signing_key.sign(data).to_bytes())
+    }
+
+    fn write_key(path: &Path, signing_key: &SigningKey) -> Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create config directory {}", dir.display()))?;
+        }
+        // Created owner-only from the start, so the key is never readable by
+        // anyone else, not even briefly
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)
+            .with_context(|| format!("Failed to create identity key {}", path.display()))?;
+        file.write_all(&signing_key.to_bytes())
+            .with_context(|| format!("Failed to write identity key {}", path.display()))?;
+
+        Ok(())
+    }
+}
+
+pub fn encode_public_key(key: &VerifyingKey) -> String {
+    BASE64.encode(key.as_bytes())
+}
+
+pub fn decode_public_key(encoded: &str) -> Result<VerifyingKey> {
+    let bytes = BASE64.decode(encoded).context("Public key is not valid base64")?;
+    let bytes: [u8; 32] = bytes.as_slice().try_into()
+        .map_err(|_| anyhow!("Public key has wrong length ({} bytes)", bytes.len()))?;
+    VerifyingKey::from_bytes(&bytes).context("Public key is not a valid Ed25519 point")
+}
+
+/// Peer ids are the first 16 bytes of SHA-256(public key), stamped as a
+/// version 8 (custom) UUID.
+pub fn peer_id_from_public_key(key: &VerifyingKey) -> Uuid {
+    let digest = Sha256::digest(key.as_bytes());
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&digest[..16]);
+    uuid::Builder::from_custom_bytes(bytes).into_uuid()
+}
+
+pub fn verify_signature(public_key: &str, data: &[u8], signature: &str) -> Result<()> {
+    let key = decode_public_key(public_key)?;
+    let bytes = BASE64.decode(signature).context("Signature is not valid base64")?;
+    let signature = Signature::from_slice(&bytes).context("Signature has wrong length")?;
+    key.verify(data, &signature).context("Signature does not verify")
+}
+
+/// Checks that `peer_id` really belongs to the advertised `public_key`.
+pub fn verify_peer_id(peer_id: &Uuid, public_key: &str) -> Result<()> {
+    let key = decode_public_key(public_key)?;
+    if peer_id_from_public_key(&key) != *peer_id {
+        return Err(anyhow!("peer id {} does not match its public key", peer_id));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_key_is_private_and_reloads_as_the_same_identity() {
+        let dir = std::env::temp_dir().join(format!("local-chat-identity-{}", Uuid::new_v4()));
+        let path = dir.join("identity.key");
+
+        let generated = Identity::load_or_generate(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = Identity::load_or_generate(&path).unwrap();
+        assert_eq!(generated.peer_id(), loaded.peer_id());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+use crate::identity;
+use crate::network::handshake::valid_username;
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::{info, warn};
+
+pub enum TrustDecision {
+    /// First time we see this username; its key has now been pinned.
+    New,
+    /// The key matches the pinned one.
+    Known,
+    /// The username is pinned to a different key.
+    Changed { pinned: String, presented: String },
+}
+
+/// SSH-style trust-on-first-use store. Each line of the `known_peers` file is
+/// `<username> <fingerprint>`; the first key seen for a username is pinned,
+/// and if the file names a username twice the earlier line wins.
+pub struct KnownPeers {
+    path: PathBuf,
+    entries: HashMap<String, String>,
+}
+
+impl KnownPeers {
+    pub fn load(path: PathBuf) -> Result<Self> {
+        let mut entries = HashMap::new();
+        if path.exists() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            for (line_no, line) in contents.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                match line.rsplit_once(' ') {
+                    Some((username, fingerprint)) => {
+                        if entries.contains_key(username) {
+                            warn!("Ignoring second pin for {} on line {} of {}", username, line_no + 1, path.display());
+                            continue;
+                        }
+                        entries.insert(username.to_string(), fingerprint.trim().to_string());
+                    }
+                    None => warn!("Ignoring malformed line {} in {}", line_no + 1, path.display()),
+                }
+            }
+            info!("Loaded {} known peers from {}", entries.len(), path.display());
+        }
+
+        Ok(Self { path, entries })
+    }
+
+    pub fn check(&mut self, username: &str, public_key: &str) -> Result<TrustDecision> {
+        let presented = fingerprint(public_key)?;
+        match self.entries.get(username) {
+            Some(pinned) if *pinned == presented => Ok(TrustDecision::Known),
+            Some(pinned) => Ok(TrustDecision::Changed { pinned: pinned.clone(), presented }),
+            None => {
+                self.pin(username, &presented)?;
+                Ok(TrustDecision::New)
+            }
+        }
+    }
+
+    /// Like `check`, but never pins anything: `New` just means the username
+    /// isn't pinned yet. For keys we only hear about second hand.
+    pub fn peek(&self, username: &str, public_key: &str) -> Result<TrustDecision> {
+        let presented = fingerprint(public_key)?;
+        match self.entries.get(username) {
+            Some(pinned) if *pinned == presented => Ok(TrustDecision::Known),
+            Some(pinned) => Ok(TrustDecision::Changed { pinned: pinned.clone(), presented }),
+            None => Ok(TrustDecision::New),
+        }
+    }
+
+    fn pin(&mut self, username: &str, fingerprint: &str) -> Result<()> {
+        if !valid_username(username) {
+            bail!("refusing to pin invalid username {:?}", username);
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .with_context(|| format!("Failed to open {}", self.path.display()))?;
+        writeln!(file, "{} {}", username, fingerprint)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+
+        info!("Pinned key {} for {}", fingerprint, username);
+        self.entries.insert(username.to_string(), fingerprint.to_string());
+        Ok(())
+    }
+}
+
+/// `SHA256:<base64>` of the raw public key, matching the format `ssh-keygen -l` uses.
+pub fn fingerprint(public_key: &str) -> Result<String> {
+    let key = identity::decode_public_key(public_key)?;
+    Ok(format!("SHA256:{}", BASE64.encode(Sha256::digest(key.as_bytes()))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use uuid::Uuid;
+
+    #[test]
+    fn pins_survive_a_reload_and_cannot_be_overridden() {
+        let dir = std::env::temp_dir().join(format!("local-chat-known-peers-{}", Uuid::new_v4()));
+        let path = dir.join("known_peers");
+        let alice = Identity::ephemeral().public_key();
+        let mallory = Identity::ephemeral().public_key();
+
+        let mut known = KnownPeers::load(path.clone()).unwrap();
+        assert!(matches!(known.check("alice", &alice).unwrap(), TrustDecision::New));
+
+        // A username carrying a fake second line is never written
+        let forged = format!("bob {}\nalice", fingerprint(&mallory).unwrap());
+        assert!(known.check(&forged, &mallory).is_err());
+
+        // Even if the file somehow names alice twice, the first pin stands
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        writeln!(file, "alice {}", fingerprint(&mallory).unwrap()).unwrap();
+
+        let mut reloaded = KnownPeers::load(path).unwrap();
+        assert!(matches!(reloaded.check("alice", &alice).unwrap(), TrustDecision::Known));
+        assert!(matches!(reloaded.check("alice", &mallory).unwrap(), TrustDecision::Changed { .. }));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
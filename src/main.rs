@@ -1,16 +1,24 @@
 mod config;
+mod identity;
+mod known_peers;
 mod message;
 mod network;
 mod ui;
 
 use anyhow::Result;
-use config::Config;
+use config::{Config, DiscoveryMode, KeyChangePolicy};
+use identity::Identity;
 use message::ChatEvent;
+use network::channel::ChannelKey;
 use network::{DiscoveryService, PeerManager};
 use std::env;
-use tokio::sync::mpsc;
-use tracing::{error, info};
-use ui::{App, TerminalUI};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Duration, Instant};
+use tracing::{error, info, warn};
+use ui::{App, ConnectionRequest, Outgoing, SendReport, TerminalUI};
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -19,61 +27,141 @@ async fn main() -> Result<()> {
     
     info!("🚀 Starting Local Chat v1.0.0");
     
-    // Parse CLI arguments: [username] [--channel|-c <name>] [--nick|-nick <username>]
+    // Parse CLI arguments: [--channel|-c <name>] [--passphrase|-p <secret>] [--nick|-nick <username>]
+    //                     [--identity <path>] [--on-key-change refuse|warn] [--relay]
+    //                     [--discovery broadcast|multicast|both] [--no-ipv6] [--mdns]
+    //                     [--peer <host:port>]...
+    // The passphrase can also come from LOCAL_CHAT_PASSPHRASE to keep it out of shell history.
     let args: Vec<String> = env::args().collect();
     let mut username: Option<String> = None;
     let mut channel: Option<String> = None;
+    let mut passphrase: Option<String> = env::var("LOCAL_CHAT_PASSPHRASE").ok();
+    let mut identity_file: Option<PathBuf> = None;
+    let mut key_change_policy = KeyChangePolicy::Refuse;
+    let mut relay = false;
+    let mut discovery_mode = DiscoveryMode::Broadcast;
+    let mut ipv6 = true;
+    let mut mdns = false;
+    let mut static_peers: Vec<String> = Vec::new();
     let mut i = 1;
     while i < args.len() {
         match args[i].as_str() {
             "--channel" | "-c" => {
                 if i + 1 < args.len() { channel = Some(args[i + 1].clone()); i += 2; } else { break; }
             }
+            "--passphrase" | "-p" => {
+                if i + 1 < args.len() { passphrase = Some(args[i + 1].clone()); i += 2; } else { break; }
+            }
             "--nick" | "-nick" => {
                 if i + 1 < args.len() { username = Some(args[i + 1].clone()); i += 2; } else { break; }
             }
+            "--identity" => {
+                if i + 1 < args.len() { identity_file = Some(PathBuf::from(&args[i + 1])); i += 2; } else { break; }
+            }
+            "--on-key-change" => {
+                if i + 1 < args.len() {
+                    key_change_policy = match args[i + 1].as_str() {
+                        "warn" => KeyChangePolicy::Warn,
+                        _ => KeyChangePolicy::Refuse,
+                    };
+                    i += 2;
+                } else { break; }
+            }
+            "--relay" => { relay = true; i += 1; }
+            "--discovery" => {
+                if i + 1 < args.len() {
+                    discovery_mode = match args[i + 1].as_str() {
+                        "multicast" => DiscoveryMode::Multicast,
+                        "both" => DiscoveryMode::Both,
+                        _ => DiscoveryMode::Broadcast,
+                    };
+                    i += 2;
+                } else { break; }
+            }
+            "--no-ipv6" => { ipv6 = false; i += 1; }
+            "--mdns" => { mdns = true; i += 1; }
+            "--peer" => {
+                if i + 1 < args.len() { static_peers.push(args[i + 1].clone()); i += 2; } else { break; }
+            }
             _ => { i += 1; }
         }
     }
-    let username = username.unwrap_or_else(|| whoami::username());
+    let username = username.unwrap_or_else(whoami::username);
+    if !network::handshake::valid_username(&username) {
+        anyhow::bail!("Invalid username {:?}: no control characters or surrounding spaces", username);
+    }
     
     // Create configuration
-    let config = Config::new().with_username(username.clone()).with_channel(channel.clone());
+    let config = Config::new()
+        .with_username(username.clone())
+        .with_channel(channel.clone())
+        .with_channel_passphrase(passphrase)
+        .with_identity_file(identity_file)
+        .with_key_change_policy(key_change_policy)
+        .with_relay(relay)
+        .with_discovery_mode(discovery_mode)
+        .with_ipv6(ipv6)
+        .with_mdns(mdns)
+        .with_static_peers(static_peers);
     info!("Starting as user: {} | channel: {}", config.username, channel.clone().unwrap_or_else(|| "(none)".into()));
     
+    // Load (or create on first run) the long-lived node identity
+    let identity = Arc::new(Identity::load_or_generate(&config.identity_path())?);
+    info!("Node identity: {}", identity.peer_id());
+    
+    // Derive the channel key once; only its tag and handshake psk are ever used on the wire
+    let channel_key = Arc::new(ChannelKey::derive(config.channel.as_deref(), config.channel_passphrase.as_deref())?);
+    let channel_tag = channel_key.tag();
+    
     // Create channels for communication between components
     let (event_sender, event_receiver) = mpsc::unbounded_channel::<ChatEvent>();
-    let (message_sender, mut message_receiver) = mpsc::unbounded_channel::<String>();
+    let (message_sender, mut message_receiver) = mpsc::unbounded_channel::<Outgoing>();
+    let (report_sender, report_receiver) = mpsc::unbounded_channel::<SendReport>();
     
     // Create peer manager
-    let peer_manager = std::sync::Arc::new(PeerManager::new(
-        config.tcp_port_range.0,
+    let peer_manager = Arc::new(PeerManager::new(
+        &config,
         event_sender.clone(),
-        username.clone(),
-        uuid::Uuid::new_v4(),
-        channel.clone(),
+        identity.clone(),
+        channel_key.clone(),
     ).await?);
     
     // Create channels for peer connection coordination
-    let (connection_sender, mut connection_receiver) = mpsc::unbounded_channel::<message::Peer>();
+    let (connection_sender, mut connection_receiver) = mpsc::unbounded_channel::<ConnectionRequest>();
     
-    // (unused) placeholder removed
+    // Seed peers are dialed like `/connect`, but for as long as it takes them to come up
+    for target in config.static_peers.clone() {
+        let peer_manager = peer_manager.clone();
+        tokio::spawn(async move {
+            if let Err(e) = peer_manager.connect_to_address(target.clone(), true).await {
+                warn!("Not dialing seed {}: {}", target, e);
+            }
+        });
+    }
     
     // Create the app with connection sender for auto-connection
-    let app = App::new(username.clone(), event_receiver, message_sender.clone(), Some(connection_sender.clone()), channel.clone());
+    let peer_timeout = chrono::Duration::seconds(config.network_timeout as i64);
+    // The app holds the only message sender, so the outgoing queue drains and
+    // closes once the UI is gone
+    let app = App::new(username.clone(), event_receiver, report_receiver, message_sender, Some(connection_sender.clone()), channel.clone(), peer_timeout);
     let mut terminal_ui = TerminalUI::new(app);
     
     // Get the actual TCP port from PeerManager
     let tcp_port = peer_manager.get_tcp_port()?;
     info!("Using TCP port {} for peer discovery", tcp_port);
     
+    // Flipped to true once we start shutting down
+    let (shutdown_sender, shutdown_receiver) = watch::channel(false);
+    
     // Start discovery service in background
+    let discovery_shutdown = shutdown_receiver.clone();
     let discovery_config = config.clone();
-    let discovery_task = tokio::spawn(async move {
-        match DiscoveryService::new(discovery_config, event_sender, tcp_port).await {
+    let discovery_identity = identity.clone();
+    let mut discovery_task = tokio::spawn(async move {
+        match DiscoveryService::new(discovery_config, event_sender, tcp_port, discovery_identity, channel_key).await {
             Ok(discovery_service) => {
                 info!("Discovery service created, starting...");
-                if let Err(e) = discovery_service.start_discovery().await {
+                if let Err(e) = discovery_service.start_discovery(discovery_shutdown).await {
                     error!("Discovery service failed: {}", e);
                 }
             }
@@ -85,7 +173,7 @@ async fn main() -> Result<()> {
     
     // Start peer manager in background
     let peer_manager_clone = peer_manager.clone();
-    let peer_manager_task = tokio::spawn(async move {
+    let mut peer_manager_task = tokio::spawn(async move {
         if let Err(e) = peer_manager_clone.start().await {
             error!("Peer manager failed: {}", e);
         }
@@ -93,11 +181,18 @@ async fn main() -> Result<()> {
     
     // Handle peer connections
     let peer_manager_for_connections = peer_manager.clone();
-    let connection_task = tokio::spawn(async move {
-        while let Some(peer) = connection_receiver.recv().await {
-            info!("Attempting to connect to peer: {}", peer.username);
-            if let Err(e) = peer_manager_for_connections.connect_to_peer(&peer).await {
-                error!("Failed to connect to peer {}: {}", peer.username, e);
+    let mut connection_task = tokio::spawn(async move {
+        while let Some(request) = connection_receiver.recv().await {
+            match request {
+                ConnectionRequest::Connect(peer) => peer_manager_for_connections.connect_or_retry(&peer).await,
+                ConnectionRequest::Forget(peer_id) => peer_manager_for_connections.forget_peer(&peer_id).await,
+                ConnectionRequest::Dial(target, reply) => {
+                    let peer_manager = peer_manager_for_connections.clone();
+                    tokio::spawn(async move {
+                        let outcome = peer_manager.connect_to_address(target, false).await;
+                        let _ = reply.send(outcome.map_err(|e| e.to_string()));
+                    });
+                }
             }
         }
     });
@@ -105,66 +200,124 @@ async fn main() -> Result<()> {
     // Handle outgoing messages
     let peer_manager_for_messages = peer_manager.clone();
     let username_for_messages = username.clone();
-    let message_task = tokio::spawn(async move {
-        while let Some(message_content) = message_receiver.recv().await {
-            info!("Broadcasting message: {}", message_content);
+    let mut message_task = tokio::spawn(async move {
+        while let Some(outgoing) = message_receiver.recv().await {
+            let (message_id, recipient, content) = match outgoing {
+                Outgoing::Broadcast { message_id, content } => (message_id, None, content),
+                Outgoing::Direct { message_id, peer_id, content } => (message_id, Some(peer_id), content),
+            };
             
-            // Create a chat message
+            // Create a chat message, signed with our identity key
             let chat_message = message::Message::chat_message(
+                message_id,
                 username_for_messages.clone(),
-                "all".to_string(),
-                message_content,
-                channel.clone(),
+                recipient.map_or_else(|| "all".to_string(), |peer_id| peer_id.to_string()),
+                content,
+                channel_tag.clone(),
+                &identity,
             );
             
-            // Broadcast to all connected peers
-            if let Err(e) = peer_manager_for_messages.broadcast_message(&chat_message).await {
-                error!("Failed to broadcast message: {}", e);
-            }
+            let sent = match recipient {
+                // Private messages go to that peer's connection only
+                Some(peer_id) => {
+                    info!("Sending private message to {}", peer_id);
+                    peer_manager_for_messages.send_message_to_peer(&peer_id, &chat_message).await
+                        .map(|acknowledges| if acknowledges { vec![peer_id] } else { Vec::new() })
+                }
+                // Everything else goes to all connected peers
+                None => {
+                    info!("Broadcasting message");
+                    peer_manager_for_messages.broadcast_message(&chat_message).await
+                }
+            };
+            
+            // Tell the UI who should confirm the message
+            let report = match sent {
+                Ok(recipients) => SendReport { message_id, recipients, error: None },
+                Err(e) => {
+                    error!("Failed to send message: {}", e);
+                    SendReport { message_id, recipients: Vec::new(), error: Some(e.to_string()) }
+                }
+            };
+            let _ = report_sender.send(report);
         }
     });
     
     // Run the terminal UI (interactive mode)
-    let ui_task = tokio::spawn(async move {
-        if let Err(e) = terminal_ui.run_interactive().await {
+    let mut ui_task = tokio::spawn(async move {
+        if let Err(e) = terminal_ui.run_interactive(shutdown_receiver).await {
             error!("Terminal UI failed: {}", e);
         }
     });
     
-    info!("All components started. Press Ctrl+C to quit.");
+    info!("All components started. Type /quit or press Ctrl+C to quit.");
     
     // Wait for any task to complete (or user to quit)
-            tokio::select! {
-            result = discovery_task => {
-                if let Err(e) = result {
-                    error!("Discovery task panicked: {}", e);
-                }
+    tokio::select! {
+        result = &mut discovery_task => {
+            if let Err(e) = result {
+                error!("Discovery task panicked: {}", e);
             }
-            result = peer_manager_task => {
-                if let Err(e) = result {
-                    error!("Peer manager task panicked: {}", e);
-                }
-            }
-            result = connection_task => {
-                if let Err(e) = result {
-                    error!("Connection task panicked: {}", e);
-                }
+        }
+        result = &mut peer_manager_task => {
+            if let Err(e) = result {
+                error!("Peer manager task panicked: {}", e);
             }
-            result = message_task => {
-                if let Err(e) = result {
-                    error!("Message task panicked: {}", e);
-                }
+        }
+        result = &mut connection_task => {
+            if let Err(e) = result {
+                error!("Connection task panicked: {}", e);
             }
-            result = ui_task => {
-                if let Err(e) = result {
-                    error!("UI task panicked: {}", e);
-                }
+        }
+        result = &mut message_task => {
+            if let Err(e) = result {
+                error!("Message task panicked: {}", e);
             }
-            _ = tokio::signal::ctrl_c() => {
-                info!("Received Ctrl+C, shutting down...");
+        }
+        result = &mut ui_task => {
+            if let Err(e) = result {
+                error!("UI task panicked: {}", e);
             }
         }
+        _ = tokio::signal::ctrl_c() => {
+            info!("Received Ctrl+C, shutting down...");
+        }
+    }
+    
+    // Coordinated shutdown: restore the terminal, send whatever is still
+    // queued, say goodbye over TCP and UDP, then stop. Each step shares one
+    // deadline so a stuck peer can't keep us from exiting.
+    let deadline = Instant::now() + Duration::from_secs(config.shutdown_timeout);
+    let _ = shutdown_sender.send(true);
+    wait_for_task("UI", ui_task, deadline).await;
+    wait_for_task("Message", message_task, deadline).await;
+    // The discovery goodbye went out with the shutdown signal; wait for it
+    // alongside the TCP ones, so slow peers can't use up its time
+    let goodbyes = async {
+        let limit = deadline.saturating_duration_since(Instant::now());
+        if timeout_at(deadline, peer_manager.shutdown(limit)).await.is_err() {
+            warn!("Timed out saying goodbye to peers");
+        }
+    };
+    tokio::join!(goodbyes, wait_for_task("Discovery", discovery_task, deadline));
+    peer_manager_task.abort();
+    connection_task.abort();
     
     info!("Local Chat shutting down. Goodbye! 👋");
     Ok(())
 }
+
+// Waits for `task` to wind down on its own, aborting it at `deadline`.
+async fn wait_for_task(name: &str, mut task: JoinHandle<()>, deadline: Instant) {
+    if task.is_finished() {
+        return;
+    }
+    match timeout_at(deadline, &mut task).await {
+        Ok(Err(e)) => error!("{} task panicked: {}", name, e),
+        Ok(Ok(())) => {}
+        Err(_) => {
+            warn!("{} task did not stop in time", name);
+            task.abort();
+        }
+    }
+}
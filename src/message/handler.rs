@@ -1,14 +1,21 @@
-use super::types::{Message, Peer, ChatEvent};
+use super::types::{Message, Peer, ChatEvent, SignatureStatus};
+use crate::identity::{self, Identity};
 use anyhow::Result;
+use chrono::Utc;
 use std::collections::HashMap;
-use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::sync::mpsc;
 use tracing::{debug, info, warn};
 use uuid::Uuid;
 
+// How far a signed `user_leave`'s timestamp may be from our clock before it
+// counts as replayed. Peers whose clocks are further off just age out.
+const LEAVE_MAX_AGE_SECS: i64 = 30;
+
 pub struct MessageHandler {
     peers: HashMap<Uuid, Peer>,
-    peer_id: Uuid,
+    identity: Arc<Identity>,
     username: String,
     event_sender: mpsc::UnboundedSender<ChatEvent>,
     tcp_port: u16,
@@ -18,13 +25,14 @@ pub struct MessageHandler {
 impl MessageHandler {
     pub fn new(
         username: String,
+        identity: Arc<Identity>,
         event_sender: mpsc::UnboundedSender<ChatEvent>,
         tcp_port: u16,
         channel: Option<String>,
     ) -> Self {
         Self {
             peers: HashMap::new(),
-            peer_id: Uuid::new_v4(),
+            identity,
             username,
             event_sender,
             tcp_port,
@@ -32,27 +40,15 @@ impl MessageHandler {
         }
     }
 
-    pub fn peer_id(&self) -> Uuid {
-        self.peer_id
-    }
-
-    pub fn username(&self) -> &str {
-        &self.username
-    }
-
-    pub fn peers(&self) -> &HashMap<Uuid, Peer> {
-        &self.peers
-    }
-
     pub fn add_peer(&mut self, peer: Peer) {
         info!("Adding peer: {} ({})", peer.username, peer.ip);
         self.peers.insert(peer.id, peer);
     }
 
-    pub fn remove_peer(&mut self, peer_id: &Uuid) {
-        if let Some(peer) = self.peers.remove(peer_id) {
-            info!("Removing peer: {} ({})", peer.username, peer.ip);
-        }
+    pub fn remove_peer(&mut self, peer_id: &Uuid) -> Option<Peer> {
+        let peer = self.peers.remove(peer_id)?;
+        info!("Removing peer: {} ({})", peer.username, peer.ip);
+        Some(peer)
     }
 
     pub fn update_peer_last_seen(&mut self, peer_id: &Uuid) {
@@ -61,76 +57,122 @@ impl MessageHandler {
         }
     }
 
-    pub fn handle_message(&mut self, message: Message, sender_ip: IpAddr) -> Result<()> {
+    /// Forgets peers we haven't heard from in `max_age`.
+    pub fn expire_peers(&mut self, max_age: chrono::Duration) {
+        let cutoff = Utc::now() - max_age;
+        self.peers.retain(|_, peer| {
+            let alive = peer.last_seen >= cutoff;
+            if !alive {
+                info!("Expiring peer: {} ({})", peer.username, peer.ip);
+            }
+            alive
+        });
+    }
+
+    /// Handles one discovery message from `sender`. Returns the reply to
+    /// send straight back to `sender`, if there is one.
+    pub fn handle_message(&mut self, message: Message, sender: SocketAddr) -> Result<Option<Message>> {
+        let sender_ip = sender.ip().to_canonical();
+        // Our own broadcasts come back to us; we are not our own peer
+        if message.origin_id() == Some(self.identity.peer_id()) {
+            debug!("Ignoring our own message from {}", sender_ip);
+            return Ok(None);
+        }
+        
         match &message {
-            Message::Discovery { username, port, peer_id, channel } => {
+            Message::Discovery { username, port, peer_id, public_key, channel } => {
                 debug!("Received discovery from {} at {}:{}", username, sender_ip, port);
                 // Filter by channel: only accept matching channel (including None==None)
                 if &self.channel != channel {
                     debug!("Ignoring discovery from {} due to channel mismatch", username);
-                    return Ok(());
+                    return Ok(None);
+                }
+                if let Err(e) = identity::verify_peer_id(peer_id, public_key) {
+                    debug!("Ignoring discovery from {}: {}", username, e);
+                    return Ok(None);
                 }
                 
                 let peer = Peer::new(username.clone(), sender_ip, *port);
                 let peer_with_id = Peer {
                     id: *peer_id,
+                    scope_id: Peer::scope_of(&sender),
                     ..peer
                 };
                 
                 self.add_peer(peer_with_id.clone());
                 
-                // Send discovery response
+                // Answer right away, so the sender needn't wait for our next
+                // broadcast to find us
                 let response = Message::discovery_response(
                     self.username.clone(),
                     self.tcp_port, // Use actual TCP port
-                    self.peer_id,
+                    self.identity.peer_id(),
+                    self.identity.public_key(),
                     self.channel.clone(),
                 );
                 
-                let event = ChatEvent::new(peer_with_id, response);
+                let event = ChatEvent::new(peer_with_id, response.clone());
                 if let Err(e) = self.event_sender.send(event) {
                     warn!("Failed to send discovery response event: {}", e);
                 }
+                return Ok(Some(response));
             }
             
-            Message::DiscoveryResponse { username, port, peer_id, channel } => {
+            Message::DiscoveryResponse { username, port, peer_id, public_key, channel } => {
                 debug!("Received discovery response from {} at {}:{}", username, sender_ip, port);
                 if &self.channel != channel {
                     debug!("Ignoring discovery response from {} due to channel mismatch", username);
-                    return Ok(());
+                    return Ok(None);
+                }
+                if let Err(e) = identity::verify_peer_id(peer_id, public_key) {
+                    debug!("Ignoring discovery response from {}: {}", username, e);
+                    return Ok(None);
                 }
                 
                 let peer = Peer::new(username.clone(), sender_ip, *port);
                 let peer_with_id = Peer {
                     id: *peer_id,
+                    scope_id: Peer::scope_of(&sender),
                     ..peer
                 };
                 
                 self.add_peer(peer_with_id.clone());
                 
-                // Send both discovery response and connect events
-                let response_event = ChatEvent::new(peer_with_id.clone(), message.clone());
+                // The UI dials the peer when it sees the response; it only
+                // counts as joined once the TCP handshake is done
+                let response_event = ChatEvent::new(peer_with_id, message.clone());
                 if let Err(e) = self.event_sender.send(response_event) {
                     warn!("Failed to send discovery response event: {}", e);
                 }
-                
-                // Send a connect trigger event
-                let connect_message = Message::user_join(username.clone(), *peer_id, self.channel.clone());
-                let connect_event = ChatEvent::new(peer_with_id, connect_message);
-                if let Err(e) = self.event_sender.send(connect_event) {
-                    warn!("Failed to send connect event: {}", e);
-                }
             }
             
-            Message::ChatMessage { sender, channel, .. } => {
+            Message::ChatMessage { sender, sender_id, channel, .. } => {
                 debug!("Received chat message from {}", sender);
                 if &self.channel != channel {
                     debug!("Ignoring chat message from {} due to channel mismatch", sender);
-                    return Ok(());
+                    return Ok(None);
+                }
+                // Private messages only travel over encrypted TCP sessions
+                if message.recipient_id().is_some() {
+                    debug!("Ignoring private message from {} sent over UDP", sender);
+                    return Ok(None);
+                }
+                // Over UDP there is no authenticated session, so only signed
+                // messages can be attributed to a peer
+                match message.verify_signature() {
+                    SignatureStatus::Verified => {}
+                    SignatureStatus::Unsigned => {
+                        debug!("Ignoring unsigned chat message claiming to be from {}", sender);
+                        return Ok(None);
+                    }
+                    SignatureStatus::Invalid(reason) => {
+                        warn!("Ignoring forged chat message claiming to be from {}: {}", sender, reason);
+                        return Ok(None);
+                    }
                 }
                 
-                if let Some(peer) = self.peers.values().find(|p| p.username == *sender).cloned() {
-                    let event = ChatEvent::new(peer, message);
+                if let Some(peer) = self.peers.get(sender_id).filter(|p| p.username == *sender).cloned() {
+                    let event = ChatEvent::new(peer, message).with_verified(true);
                     if let Err(e) = self.event_sender.send(event) {
                         warn!("Failed to send chat message event: {}", e);
                     }
@@ -141,7 +183,7 @@ impl MessageHandler {
                 debug!("User {} joined", username);
                 if &self.channel != channel {
                     debug!("Ignoring user join for {} due to channel mismatch", username);
-                    return Ok(());
+                    return Ok(None);
                 }
                 self.update_peer_last_seen(peer_id);
                 
@@ -153,15 +195,31 @@ impl MessageHandler {
                 }
             }
             
-            Message::UserLeave { username, peer_id, channel, .. } => {
+            Message::UserLeave { username, peer_id, channel, timestamp, .. } => {
                 debug!("User {} left", username);
                 if &self.channel != channel {
                     debug!("Ignoring user leave for {} due to channel mismatch", username);
-                    return Ok(());
+                    return Ok(None);
                 }
-                self.remove_peer(peer_id);
-                
-                if let Some(peer) = self.peers.get(peer_id).cloned() {
+                // Anyone can put a peer id in a datagram, so only the peer's
+                // own key (the one its id is derived from) can say it left
+                match message.verify_signature() {
+                    SignatureStatus::Verified => {}
+                    SignatureStatus::Unsigned => {
+                        debug!("Ignoring unsigned user leave for {}", username);
+                        return Ok(None);
+                    }
+                    SignatureStatus::Invalid(reason) => {
+                        warn!("Ignoring forged user leave for {}: {}", username, reason);
+                        return Ok(None);
+                    }
+                }
+                // A goodbye recorded earlier and replayed once the peer is back
+                if (Utc::now() - *timestamp).abs() > chrono::Duration::seconds(LEAVE_MAX_AGE_SECS) {
+                    debug!("Ignoring stale user leave for {} from {}", username, timestamp);
+                    return Ok(None);
+                }
+                if let Some(peer) = self.remove_peer(peer_id) {
                     let event = ChatEvent::new(peer, message);
                     if let Err(e) = self.event_sender.send(event) {
                         warn!("Failed to send user leave event: {}", e);
@@ -169,12 +227,68 @@ impl MessageHandler {
                 }
             }
             
-            Message::Heartbeat { peer_id, .. } => {
+            Message::Heartbeat { peer_id, .. } | Message::HeartbeatAck { peer_id, .. } => {
                 debug!("Received heartbeat from peer {}", peer_id);
                 self.update_peer_last_seen(peer_id);
             }
+            
+            Message::MessageAck { message_id, .. } => {
+                debug!("Ignoring delivery receipt for {} outside of a TCP session", message_id);
+            }
+            
+            Message::HistoryRequest { .. } | Message::HistoryResponse { .. } => {
+                debug!("Ignoring history sync from {} outside of a TCP session", sender_ip);
+            }
+            
+            Message::PeerList { .. } => {
+                debug!("Ignoring peer list from {} outside of a TCP session", sender_ip);
+            }
+            
+            Message::JoinRejected { reason, .. } => {
+                debug!("Ignoring join rejection outside of a TCP handshake: {}", reason);
+            }
         }
         
-        Ok(())
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const SENDER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), 7878);
+
+    fn discovery_from(identity: &Identity, username: &str) -> Message {
+        Message::discovery(username.to_string(), 8000, identity.peer_id(), identity.public_key(), None)
+    }
+
+    #[test]
+    fn only_the_peers_own_signed_leave_removes_it() {
+        let (event_sender, mut events) = mpsc::unbounded_channel();
+        let mut handler = MessageHandler::new("me".to_string(), Arc::new(Identity::ephemeral()), event_sender, 8000, None);
+        let bob = Identity::ephemeral();
+        handler.handle_message(discovery_from(&bob, "bob"), SENDER).unwrap();
+        while events.try_recv().is_ok() {}
+
+        // Anyone on the LAN can send these
+        let unsigned = Message::user_leave("bob".to_string(), bob.peer_id(), None);
+        let mut forged = Message::signed_user_leave("bob".to_string(), None, &Identity::ephemeral());
+        if let Message::UserLeave { peer_id, .. } = &mut forged {
+            *peer_id = bob.peer_id();
+        }
+        for leave in [unsigned, forged] {
+            handler.handle_message(leave, SENDER).unwrap();
+            assert!(handler.peers.contains_key(&bob.peer_id()));
+            assert!(events.try_recv().is_err());
+        }
+
+        let genuine = Message::signed_user_leave("bob".to_string(), None, &bob);
+        handler.handle_message(genuine, SENDER).unwrap();
+        assert!(!handler.peers.contains_key(&bob.peer_id()));
+        let event = events.try_recv().unwrap();
+        assert!(matches!(event.message, Message::UserLeave { .. }));
+        assert_eq!(event.peer.id, bob.peer_id());
     }
 }
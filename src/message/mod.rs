@@ -1,5 +1,5 @@
 pub mod types;
 pub mod handler;
 
-pub use types::{Message, Peer, ChatEvent};
+pub use types::{Capabilities, Message, Peer, PeerListEntry, PeerStatus, ChatEvent, SignatureStatus};
 pub use handler::MessageHandler;
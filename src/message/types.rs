@@ -1,8 +1,92 @@
+use crate::identity::{self, Identity};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::net::IpAddr;
+use serde_json::json;
+use std::net::{IpAddr, SocketAddr, SocketAddrV6};
+use std::ops::BitOr;
 use uuid::Uuid;
 
+// Range of TCP protocol versions this build can speak. Bump the max when the
+// wire format changes in a way older builds can't follow, and the min when we
+// drop support for an old format.
+//
+// v2: every TCP connection starts with a Noise handshake and `user_join`
+// carries a signature over the handshake hash.
+// v3: the handshake mixes in a channel key (NNpsk0) and `channel` fields carry
+// an opaque tag instead of the channel name.
+// v4: both sides send `heartbeat`s, answer them with `heartbeat_ack`, and drop
+// a session that stays silent for longer than `network_timeout`.
+// v5: every chat message received over TCP is confirmed with a `message_ack`.
+// v6: right after the handshake a peer may send `history_request`, answered
+// with a `history_response` carrying recent signed chat messages.
+// v7: chat messages carry a `hop_limit`, and group messages signed by someone
+// other than the connection's peer are accepted as relayed.
+// v8: when two peers dial each other at once, the connection that loses the
+// tie-break is closed with a `join_rejected` instead of just dropped.
+// v9: peers send each other a `peer_list` of who else they are connected to,
+// right after the handshake and every `pex_interval` afterwards.
+pub const PROTOCOL_VERSION_MIN: u16 = 3;
+pub const PROTOCOL_VERSION_MAX: u16 = 9;
+
+/// How many hops a chat message may travel through relaying peers.
+pub const DEFAULT_HOP_LIMIT: u8 = 4;
+
+/// Optional features a peer advertises in its `UserJoin`. Serialized as a
+/// plain bit set so older builds simply ignore bits they don't know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const DIRECT_MESSAGES: Self = Self(1 << 0);
+    pub const FILE_TRANSFER: Self = Self(1 << 1);
+    pub const ENCRYPTION: Self = Self(1 << 2);
+    pub const COMPRESSION: Self = Self(1 << 3);
+
+    /// Features implemented by this build.
+    pub const fn supported() -> Self {
+        Self(Self::ENCRYPTION.0 | Self::DIRECT_MESSAGES.0)
+    }
+
+    pub fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub fn names(self) -> Vec<&'static str> {
+        [
+            (Self::DIRECT_MESSAGES, "dm"),
+            (Self::FILE_TRANSFER, "file_transfer"),
+            (Self::ENCRYPTION, "encryption"),
+            (Self::COMPRESSION, "compression"),
+        ]
+        .into_iter()
+        .filter(|(flag, _)| self.0 & flag.0 != 0)
+        .map(|(_, name)| name)
+        .collect()
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+/// One peer in a `peer_list`: who it is and where it can be dialed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PeerListEntry {
+    pub peer_id: Uuid,
+    pub username: String,
+    pub ip: IpAddr,
+    pub port: u16, // its TCP listener
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
     pub id: Uuid,
@@ -10,6 +94,10 @@ pub struct Peer {
     pub ip: IpAddr,
     pub port: u16,
     pub last_seen: DateTime<Utc>,
+    #[serde(default)]
+    pub rtt_ms: Option<u64>, // latest heartbeat round trip, if connected
+    #[serde(default)]
+    pub scope_id: u32, // interface index for IPv6 link-local addresses, 0 otherwise
 }
 
 impl Peer {
@@ -20,6 +108,25 @@ impl Peer {
             ip,
             port,
             last_seen: Utc::now(),
+            rtt_ms: None,
+            scope_id: 0,
+        }
+    }
+
+    /// Where to dial this peer; link-local IPv6 addresses only mean something
+    /// together with the interface they were seen on.
+    pub fn socket_addr(&self) -> SocketAddr {
+        match self.ip {
+            IpAddr::V6(ip) => SocketAddr::V6(SocketAddrV6::new(ip, self.port, 0, self.scope_id)),
+            IpAddr::V4(_) => SocketAddr::new(self.ip, self.port),
+        }
+    }
+
+    /// The interface index `addr` is scoped to, if it has one.
+    pub fn scope_of(addr: &SocketAddr) -> u32 {
+        match addr {
+            SocketAddr::V6(addr) => addr.scope_id(),
+            SocketAddr::V4(_) => 0,
         }
     }
 
@@ -36,6 +143,8 @@ pub enum Message {
         username: String,
         port: u16,
         peer_id: Uuid,
+        #[serde(default)]
+        public_key: String,
         channel: Option<String>,
     },
     #[serde(rename = "discovery_response")]
@@ -43,23 +152,58 @@ pub enum Message {
         username: String,
         port: u16,
         peer_id: Uuid,
+        #[serde(default)]
+        public_key: String,
         channel: Option<String>,
     },
     #[serde(rename = "message")]
     ChatMessage {
         sender: String,
-        recipient: String, // "all" for broadcast
+        // Older builds send unsigned messages; they parse with these empty and
+        // are shown as unverified.
+        #[serde(default)]
+        sender_id: Uuid,
+        #[serde(default)]
+        public_key: String,
+        recipient: String, // "all" for broadcast, otherwise the recipient's peer id
         content: String,
         timestamp: DateTime<Utc>,
         message_id: Uuid,
         channel: Option<String>,
+        // Identity-key signature over `signing_payload()`
+        #[serde(default)]
+        signature: String,
+        // Hops left, decremented by each relay, so it isn't signed. Older
+        // builds omit it and their messages are never relayed.
+        #[serde(default)]
+        hop_limit: u8,
     },
     #[serde(rename = "user_join")]
     UserJoin {
         username: String,
         peer_id: Uuid,
+        #[serde(default)]
+        public_key: String,
         timestamp: DateTime<Utc>,
         channel: Option<String>,
+        // Builds from before version negotiation omit these and end up with
+        // version 0, which no current build accepts.
+        #[serde(default)]
+        min_version: u16,
+        #[serde(default)]
+        max_version: u16,
+        #[serde(default)]
+        capabilities: Capabilities,
+        // Identity-key signature over the Noise handshake hash; proves the
+        // sender owns `public_key` and ties it to this particular session.
+        #[serde(default)]
+        session_signature: String,
+    },
+    #[serde(rename = "join_rejected")]
+    JoinRejected {
+        reason: String,
+        min_version: u16,
+        max_version: u16,
     },
     #[serde(rename = "user_leave")]
     UserLeave {
@@ -67,50 +211,108 @@ pub enum Message {
         peer_id: Uuid,
         timestamp: DateTime<Utc>,
         channel: Option<String>,
+        // Only set on the goodbye broadcast over UDP, where nothing else
+        // vouches for the sender: its identity key and a signature over
+        // `signing_payload()`. Leaves over TCP are covered by the session.
+        #[serde(default)]
+        public_key: String,
+        #[serde(default)]
+        signature: String,
     },
     #[serde(rename = "heartbeat")]
     Heartbeat {
         peer_id: Uuid,
         timestamp: DateTime<Utc>,
     },
+    #[serde(rename = "heartbeat_ack")]
+    HeartbeatAck {
+        peer_id: Uuid,
+        timestamp: DateTime<Utc>, // echoed from the heartbeat, so the sender can time the round trip
+    },
+    #[serde(rename = "message_ack")]
+    MessageAck {
+        message_id: Uuid, // the chat message being confirmed
+        peer_id: Uuid,
+    },
+    #[serde(rename = "history_request")]
+    HistoryRequest {
+        limit: usize, // most recent messages wanted
+        channel: Option<String>,
+    },
+    #[serde(rename = "history_response")]
+    HistoryResponse {
+        messages: Vec<Message>, // signed chat messages, oldest first
+    },
+    #[serde(rename = "peer_list")]
+    PeerList {
+        peers: Vec<PeerListEntry>, // peers the sender is connected to, other than the recipient
+        channel: Option<String>,
+    },
 }
 
 impl Message {
-    pub fn discovery(username: String, port: u16, peer_id: Uuid, channel: Option<String>) -> Self {
+    pub fn discovery(username: String, port: u16, peer_id: Uuid, public_key: String, channel: Option<String>) -> Self {
         Message::Discovery {
             username,
             port,
             peer_id,
+            public_key,
             channel,
         }
     }
 
-    pub fn discovery_response(username: String, port: u16, peer_id: Uuid, channel: Option<String>) -> Self {
+    pub fn discovery_response(username: String, port: u16, peer_id: Uuid, public_key: String, channel: Option<String>) -> Self {
         Message::DiscoveryResponse {
             username,
             port,
             peer_id,
+            public_key,
             channel,
         }
     }
 
-    pub fn chat_message(sender: String, recipient: String, content: String, channel: Option<String>) -> Self {
-        Message::ChatMessage {
+    /// Builds a chat message signed with `identity`. The caller picks the
+    /// `message_id` so it can match up delivery receipts.
+    pub fn chat_message(message_id: Uuid, sender: String, recipient: String, content: String, channel: Option<String>, identity: &Identity) -> Self {
+        let mut message = Message::ChatMessage {
             sender,
+            sender_id: identity.peer_id(),
+            public_key: identity.public_key(),
             recipient,
             content,
             timestamp: Utc::now(),
-            message_id: Uuid::new_v4(),
+            message_id,
             channel,
+            signature: String::new(),
+            hop_limit: DEFAULT_HOP_LIMIT,
+        };
+        if let Some(payload) = message.signing_payload() {
+            if let Message::ChatMessage { signature, .. } = &mut message {
+                *signature = identity.sign(&payload);
+            }
         }
+        message
     }
 
-    pub fn user_join(username: String, peer_id: Uuid, channel: Option<String>) -> Self {
+    pub fn user_join(username: String, peer_id: Uuid, public_key: String, channel: Option<String>) -> Self {
         Message::UserJoin {
             username,
             peer_id,
+            public_key,
             timestamp: Utc::now(),
             channel,
+            min_version: PROTOCOL_VERSION_MIN,
+            max_version: PROTOCOL_VERSION_MAX,
+            capabilities: Capabilities::supported(),
+            session_signature: String::new(),
+        }
+    }
+
+    pub fn join_rejected(reason: String) -> Self {
+        Message::JoinRejected {
+            reason,
+            min_version: PROTOCOL_VERSION_MIN,
+            max_version: PROTOCOL_VERSION_MAX,
         }
     }
 
@@ -120,7 +322,24 @@ impl Message {
             peer_id,
             timestamp: Utc::now(),
             channel,
+            public_key: String::new(),
+            signature: String::new(),
+        }
+    }
+
+    /// Builds our goodbye for broadcasting over UDP, signed with `identity`
+    /// so nobody else can announce that we left.
+    pub fn signed_user_leave(username: String, channel: Option<String>, identity: &Identity) -> Self {
+        let mut message = Message::user_leave(username, identity.peer_id(), channel);
+        if let Message::UserLeave { public_key, .. } = &mut message {
+            *public_key = identity.public_key();
+        }
+        if let Some(payload) = message.signing_payload() {
+            if let Message::UserLeave { signature, .. } = &mut message {
+                *signature = identity.sign(&payload);
+            }
         }
+        message
     }
 
     pub fn heartbeat(peer_id: Uuid) -> Self {
@@ -129,16 +348,153 @@ impl Message {
             timestamp: Utc::now(),
         }
     }
+
+    pub fn heartbeat_ack(peer_id: Uuid, timestamp: DateTime<Utc>) -> Self {
+        Message::HeartbeatAck {
+            peer_id,
+            timestamp,
+        }
+    }
+
+    pub fn message_ack(message_id: Uuid, peer_id: Uuid) -> Self {
+        Message::MessageAck {
+            message_id,
+            peer_id,
+        }
+    }
+
+    pub fn history_request(limit: usize, channel: Option<String>) -> Self {
+        Message::HistoryRequest {
+            limit,
+            channel,
+        }
+    }
+
+    pub fn history_response(messages: Vec<Message>) -> Self {
+        Message::HistoryResponse {
+            messages,
+        }
+    }
+
+    pub fn peer_list(peers: Vec<PeerListEntry>, channel: Option<String>) -> Self {
+        Message::PeerList {
+            peers,
+            channel,
+        }
+    }
+
+    /// The peer a message says it comes from, if it names one.
+    pub fn origin_id(&self) -> Option<Uuid> {
+        match self {
+            Message::Discovery { peer_id, .. }
+            | Message::DiscoveryResponse { peer_id, .. }
+            | Message::UserJoin { peer_id, .. }
+            | Message::UserLeave { peer_id, .. }
+            | Message::Heartbeat { peer_id, .. }
+            | Message::HeartbeatAck { peer_id, .. }
+            | Message::MessageAck { peer_id, .. } => Some(*peer_id),
+            Message::ChatMessage { sender_id, .. } => Some(*sender_id),
+            Message::JoinRejected { .. }
+            | Message::HistoryRequest { .. }
+            | Message::HistoryResponse { .. }
+            | Message::PeerList { .. } => None,
+        }
+    }
+
+    /// The peer a private chat message is addressed to; None for broadcasts.
+    pub fn recipient_id(&self) -> Option<Uuid> {
+        match self {
+            Message::ChatMessage { recipient, .. } => recipient.parse().ok(),
+            _ => None,
+        }
+    }
+
+    // Every field of a chat message or leave except the signature itself, in
+    // a fixed order, so re-serializing on the receiving side yields the same
+    // bytes. Leaves are tagged so one can't pass for the other.
+    fn signing_payload(&self) -> Option<Vec<u8>> {
+        let payload = match self {
+            Message::ChatMessage { sender, sender_id, public_key, recipient, content, timestamp, message_id, channel, .. } => {
+                json!([message_id, sender_id, public_key, sender, recipient, channel, timestamp, content])
+            }
+            Message::UserLeave { username, peer_id, public_key, timestamp, channel, .. } => {
+                json!(["user_leave", peer_id, public_key, username, channel, timestamp])
+            }
+            _ => return None,
+        };
+        Some(payload.to_string().into_bytes())
+    }
+
+    /// Checks a chat message's or leave's signature against the key it
+    /// carries, and that key against the peer id it claims. This proves who
+    /// wrote it; callers still have to check that it is who they expect.
+    pub fn verify_signature(&self) -> SignatureStatus {
+        let (signer_id, public_key, signature) = match self {
+            Message::ChatMessage { sender_id, public_key, signature, .. } => (sender_id, public_key, signature),
+            Message::UserLeave { peer_id, public_key, signature, .. } => (peer_id, public_key, signature),
+            _ => return SignatureStatus::Unsigned,
+        };
+        if signature.is_empty() {
+            return SignatureStatus::Unsigned;
+        }
+        if let Err(e) = identity::verify_peer_id(signer_id, public_key) {
+            return SignatureStatus::Invalid(e.to_string());
+        }
+        let Some(payload) = self.signing_payload() else {
+            return SignatureStatus::Unsigned;
+        };
+        match identity::verify_signature(public_key, &payload, signature) {
+            Ok(()) => SignatureStatus::Verified,
+            Err(e) => SignatureStatus::Invalid(format!("{:#}", e)),
+        }
+    }
+}
+
+pub enum SignatureStatus {
+    Verified,
+    Unsigned,
+    Invalid(String),
+}
+
+/// Where we stand with a peer whose connection we lost without a goodbye.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeerStatus {
+    Reconnecting { attempt: u32 }, // 0 until the first retry
+    Offline, // gave up; discovery no longer sees the peer
 }
 
 #[derive(Debug, Clone)]
 pub struct ChatEvent {
     pub peer: Peer,
     pub message: Message,
+    pub warning: Option<String>,
+    pub verified: bool, // chat message signature checked against `peer`
+    pub backfilled: bool, // chat message from another peer's history, not sent live
+    pub status: Option<PeerStatus>, // on a user_leave we made up ourselves: what we're doing about it
 }
 
 impl ChatEvent {
     pub fn new(peer: Peer, message: Message) -> Self {
-        Self { peer, message }
+        Self { peer, message, warning: None, verified: false, backfilled: false, status: None }
+    }
+
+    pub fn with_verified(mut self, verified: bool) -> Self {
+        self.verified = verified;
+        self
+    }
+
+    pub fn with_backfilled(mut self, backfilled: bool) -> Self {
+        self.backfilled = backfilled;
+        self
+    }
+
+    pub fn with_warning(mut self, warning: String) -> Self {
+        self.warning = Some(warning);
+        self
+    }
+
+    pub fn with_status(mut self, status: PeerStatus) -> Self {
+        self.status = Some(status);
+        self
     }
 }
@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Result};
+use argon2::Argon2;
+use hkdf::Hkdf;
+use sha2::{Digest, Sha256};
+
+const SALT_PREFIX: &str = "local-chat channel:";
+const TAG_LEN: usize = 8;
+
+/// Secret material for the channel this node is in.
+///
+/// The channel name and optional passphrase never go on the wire. Instead,
+/// peers compare an opaque `tag` in discovery and message `channel` fields,
+/// and mix the `psk` into every Noise handshake, so a peer that doesn't know
+/// the passphrase can neither complete a session nor decrypt its traffic.
+pub struct ChannelKey {
+    psk: [u8; 32],
+    tag: Option<String>,
+}
+
+impl ChannelKey {
+    /// Derives the key for `name`. With a passphrase the master secret comes
+    /// from Argon2id, so a captured tag can't be cheaply brute-forced; without
+    /// one it is just a hash of the name (anyone who guesses it can join).
+    pub fn derive(name: Option<&str>, passphrase: Option<&str>) -> Result<Self> {
+        let Some(name) = name else {
+            if passphrase.is_some() {
+                return Err(anyhow!("A channel passphrase requires a channel name (--channel)"));
+            }
+            return Ok(Self::global());
+        };
+
+        let salt = format!("{}{}", SALT_PREFIX, name);
+        let mut master = [0u8; 32];
+        match passphrase {
+            Some(passphrase) => Argon2::default()
+                .hash_password_into(passphrase.as_bytes(), salt.as_bytes(), &mut master)
+                .map_err(|e| anyhow!("Failed to derive channel key: {}", e))?,
+            None => master.copy_from_slice(&Sha256::digest(salt.as_bytes())),
+        }
+
+        let hkdf = Hkdf::<Sha256>::new(None, &master);
+        let mut psk = [0u8; 32];
+        let mut tag = [0u8; TAG_LEN];
+        hkdf.expand(b"noise psk", &mut psk)
+            .map_err(|e| anyhow!("Failed to derive channel key: {}", e))?;
+        hkdf.expand(b"channel tag", &mut tag)
+            .map_err(|e| anyhow!("Failed to derive channel tag: {}", e))?;
+
+        Ok(Self {
+            psk,
+            tag: Some(tag.iter().map(|b| format!("{:02x}", b)).collect()),
+        })
+    }
+
+    // The global room (no channel) still runs the psk handshake, just with a
+    // well-known key.
+    fn global() -> Self {
+        let mut psk = [0u8; 32];
+        psk.copy_from_slice(&Sha256::digest(SALT_PREFIX.as_bytes()));
+        Self { psk, tag: None }
+    }
+
+    /// Value to put in (and compare against) the `channel` field of messages.
+    pub fn tag(&self) -> Option<String> {
+        self.tag.clone()
+    }
+
+    pub fn psk(&self) -> &[u8; 32] {
+        &self.psk
+    }
+}
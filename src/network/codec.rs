@@ -0,0 +1,350 @@
+use super::noise::{NOISE_MAX_MESSAGE_LEN, NOISE_TAG_LEN};
+use crate::message::Message;
+use anyhow::{Context, Result};
+use snow::StatelessTransportState;
+use std::fmt;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+// Every frame on the wire is a 4-byte big-endian length followed by that many
+// bytes of payload (a JSON-encoded `Message`). Once a Noise session is set up
+// the payload is encrypted in chunks of at most one Noise message each; every
+// chunk but the last is exactly NOISE_MAX_MESSAGE_LEN bytes on the wire, so
+// the receiver can split them back up without extra framing.
+const LENGTH_PREFIX_SIZE: usize = 4;
+const MAX_CHUNK_PLAINTEXT: usize = NOISE_MAX_MESSAGE_LEN - NOISE_TAG_LEN;
+
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    Oversized { len: usize, max: usize },
+    Truncated { expected: usize, received: usize },
+    Malformed(serde_json::Error),
+    Crypto(snow::Error),
+    Io(std::io::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Oversized { len, max } => {
+                write!(f, "frame of {} bytes exceeds maximum frame size of {} bytes", len, max)
+            }
+            FrameError::Truncated { expected, received } => {
+                write!(f, "connection closed mid-frame ({} of {} bytes received)", received, expected)
+            }
+            FrameError::Malformed(e) => write!(f, "malformed message: {}", e),
+            FrameError::Crypto(e) => write!(f, "failed to decrypt frame: {}", e),
+            FrameError::Io(e) => write!(f, "frame I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FrameError::Malformed(e) => Some(e),
+            FrameError::Crypto(e) => Some(e),
+            FrameError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FrameError {
+    fn from(e: std::io::Error) -> Self {
+        FrameError::Io(e)
+    }
+}
+
+impl From<snow::Error> for FrameError {
+    fn from(e: snow::Error) -> Self {
+        FrameError::Crypto(e)
+    }
+}
+
+// One direction of a Noise transport. Nonces are implicit: both ends count
+// the chunks they have sent/received.
+struct Cipher {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+fn encrypted_len(plaintext_len: usize) -> usize {
+    let chunks = plaintext_len.div_ceil(MAX_CHUNK_PLAINTEXT).max(1);
+    plaintext_len + chunks * NOISE_TAG_LEN
+}
+
+pub struct FrameReader<R> {
+    inner: R,
+    max_frame_size: usize,
+    cipher: Option<Cipher>,
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    pub fn new(inner: R, max_frame_size: usize) -> Self {
+        Self { inner, max_frame_size, cipher: None }
+    }
+
+    /// Decrypts every frame read from now on with `transport`.
+    pub fn set_transport(&mut self, transport: Arc<StatelessTransportState>) {
+        self.cipher = Some(Cipher { transport, nonce: 0 });
+    }
+
+    /// Reads one frame. Returns `Ok(None)` if the peer closed the connection
+    /// cleanly on a frame boundary.
+    pub async fn read_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let Some(wire) = self.read_wire_frame().await? else {
+            return Ok(None);
+        };
+        let Some(cipher) = &mut self.cipher else {
+            return Ok(Some(wire));
+        };
+        if wire.is_empty() {
+            // Encrypted frames always carry at least one authentication tag
+            return Err(FrameError::Crypto(snow::Error::Decrypt));
+        }
+
+        let mut payload = Vec::with_capacity(wire.len());
+        let mut buf = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+        for chunk in wire.chunks(NOISE_MAX_MESSAGE_LEN) {
+            let len = cipher.transport.read_message(cipher.nonce, chunk, &mut buf)?;
+            cipher.nonce += 1;
+            payload.extend_from_slice(&buf[..len]);
+        }
+
+        if payload.len() > self.max_frame_size {
+            return Err(FrameError::Oversized { len: payload.len(), max: self.max_frame_size });
+        }
+        Ok(Some(payload))
+    }
+
+    async fn read_wire_frame(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        let received = self.read_fully(&mut prefix).await?;
+        if received == 0 {
+            return Ok(None);
+        }
+        if received < LENGTH_PREFIX_SIZE {
+            return Err(FrameError::Truncated { expected: LENGTH_PREFIX_SIZE, received });
+        }
+
+        let len = u32::from_be_bytes(prefix) as usize;
+        let max = match self.cipher {
+            Some(_) => encrypted_len(self.max_frame_size),
+            None => self.max_frame_size,
+        };
+        if len > max {
+            return Err(FrameError::Oversized { len, max });
+        }
+
+        let mut payload = vec![0u8; len];
+        let received = self.read_fully(&mut payload).await?;
+        if received < len {
+            return Err(FrameError::Truncated { expected: len, received });
+        }
+
+        Ok(Some(payload))
+    }
+
+    /// Reads the next message we understand, skipping frames that carry
+    /// message types from newer builds. Anything else that doesn't parse is
+    /// a protocol error.
+    pub async fn read_message(&mut self) -> Result<Option<Message>, FrameError> {
+        loop {
+            let Some(payload) = self.read_frame().await? else {
+                return Ok(None);
+            };
+
+            match serde_json::from_slice::<Message>(&payload) {
+                Ok(message) => return Ok(Some(message)),
+                Err(e) => match unknown_type(&payload, &e) {
+                    Some(kind) => debug!("Skipping message of unknown type '{}'", kind),
+                    None => return Err(FrameError::Malformed(e)),
+                },
+            }
+        }
+    }
+
+    // Like `read_exact`, but reports how many bytes arrived before EOF instead
+    // of failing, so callers can tell a clean close from a truncated frame.
+    async fn read_fully(&mut self, buf: &mut [u8]) -> Result<usize, FrameError> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = self.inner.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+        Ok(filled)
+    }
+}
+
+pub struct FrameWriter<W> {
+    inner: W,
+    max_frame_size: usize,
+    cipher: Option<Cipher>,
+}
+
+impl<W: AsyncWrite + Unpin> FrameWriter<W> {
+    pub fn new(inner: W, max_frame_size: usize) -> Self {
+        Self { inner, max_frame_size, cipher: None }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Encrypts every frame written from now on with `transport`.
+    pub fn set_transport(&mut self, transport: Arc<StatelessTransportState>) {
+        self.cipher = Some(Cipher { transport, nonce: 0 });
+    }
+
+    pub async fn write_frame(&mut self, payload: &[u8]) -> Result<(), FrameError> {
+        if payload.len() > self.max_frame_size {
+            return Err(FrameError::Oversized { len: payload.len(), max: self.max_frame_size });
+        }
+
+        let encrypted;
+        let wire = match &mut self.cipher {
+            Some(cipher) => {
+                let mut out = vec![0u8; encrypted_len(payload.len())];
+                let mut written = 0;
+                // An empty payload still produces one (tag-only) chunk
+                let chunks: Vec<&[u8]> = if payload.is_empty() {
+                    vec![payload]
+                } else {
+                    payload.chunks(MAX_CHUNK_PLAINTEXT).collect()
+                };
+                for chunk in chunks {
+                    written += cipher.transport.write_message(cipher.nonce, chunk, &mut out[written..])?;
+                    cipher.nonce += 1;
+                }
+                encrypted = out;
+                &encrypted[..]
+            }
+            None => payload,
+        };
+
+        let prefix = (wire.len() as u32).to_be_bytes();
+        self.inner.write_all(&prefix).await?;
+        self.inner.write_all(wire).await?;
+        self.inner.flush().await?;
+        Ok(())
+    }
+
+    pub async fn write_message(&mut self, message: &Message) -> Result<()> {
+        let data = serde_json::to_vec(message)
+            .context("Failed to serialize message")?;
+        self.write_frame(&data).await
+            .context("Failed to write message frame")?;
+        Ok(())
+    }
+
+    /// Flushes anything still buffered and closes our direction of the
+    /// stream, so the peer reads a clean end of stream.
+    pub async fn shutdown(&mut self) -> Result<(), FrameError> {
+        self.inner.shutdown().await?;
+        Ok(())
+    }
+}
+
+// The `type` of a frame that failed to parse only because no `Message`
+// variant has that tag; a newer build's message rather than a broken one.
+fn unknown_type(payload: &[u8], error: &serde_json::Error) -> Option<String> {
+    if !error.to_string().starts_with("unknown variant") {
+        return None;
+    }
+    let value: serde_json::Value = serde_json::from_slice(payload).ok()?;
+    value.get("type")?.as_str().map(str::to_owned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+    use uuid::Uuid;
+
+    const MAX: usize = 64;
+
+    // A reader for whatever `bytes` the other end wrote before hanging up.
+    async fn reader_for(bytes: &[u8]) -> FrameReader<DuplexStream> {
+        let (mut tx, rx) = duplex(1024);
+        tx.write_all(bytes).await.unwrap();
+        drop(tx);
+        FrameReader::new(rx, MAX)
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_both_ways() {
+        let mut reader = reader_for(&(MAX as u32 + 1).to_be_bytes()).await;
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::Oversized { len, max: MAX }) if len == MAX + 1
+        ));
+
+        let (tx, _rx) = duplex(1024);
+        let mut writer = FrameWriter::new(tx, MAX);
+        assert!(matches!(
+            writer.write_frame(&[0u8; MAX + 1]).await,
+            Err(FrameError::Oversized { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_length_prefix_is_an_error() {
+        let mut reader = reader_for(&[0, 0]).await;
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::Truncated { expected: LENGTH_PREFIX_SIZE, received: 2 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn truncated_body_is_an_error() {
+        let mut bytes = 10u32.to_be_bytes().to_vec();
+        bytes.extend_from_slice(b"abc");
+        let mut reader = reader_for(&bytes).await;
+        assert!(matches!(
+            reader.read_frame().await,
+            Err(FrameError::Truncated { expected: 10, received: 3 })
+        ));
+    }
+
+    #[tokio::test]
+    async fn clean_close_on_a_frame_boundary_is_not_an_error() {
+        let mut reader = reader_for(&[]).await;
+        assert!(matches!(reader.read_frame().await, Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn unknown_message_types_are_skipped() {
+        let (tx, rx) = duplex(1024);
+        let mut writer = FrameWriter::new(tx, MAX * 4);
+        writer.write_frame(br#"{"type":"file_offer","name":"a.txt"}"#).await.unwrap();
+        let heartbeat = Message::heartbeat(Uuid::new_v4());
+        writer.write_message(&heartbeat).await.unwrap();
+        drop(writer);
+
+        let mut reader = FrameReader::new(rx, MAX * 4);
+        let message = reader.read_message().await.unwrap();
+        assert!(matches!(message, Some(Message::Heartbeat { .. })));
+        assert!(reader.read_message().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_messages_are_an_error() {
+        for payload in [&b"not json"[..], br#"{"type":"heartbeat"}"#, br#"{"name":"a.txt"}"#] {
+            let (tx, rx) = duplex(1024);
+            let mut writer = FrameWriter::new(tx, MAX);
+            writer.write_frame(payload).await.unwrap();
+            drop(writer);
+
+            let mut reader = FrameReader::new(rx, MAX);
+            assert!(matches!(reader.read_message().await, Err(FrameError::Malformed(_))));
+        }
+    }
+}
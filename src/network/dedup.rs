@@ -0,0 +1,35 @@
+use std::collections::{HashSet, VecDeque};
+use uuid::Uuid;
+
+/// The last `capacity` message ids we have seen, so a message that arrives
+/// twice (e.g. replayed from a peer's outbox after a reconnect) is only shown
+/// once.
+pub struct SeenMessages {
+    ids: HashSet<Uuid>,
+    order: VecDeque<Uuid>,
+    capacity: usize,
+}
+
+impl SeenMessages {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ids: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    /// Records `id`. Returns false if it was already seen.
+    pub fn insert(&mut self, id: Uuid) -> bool {
+        if !self.ids.insert(id) {
+            return false;
+        }
+        self.order.push_back(id);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
@@ -1,29 +1,43 @@
 use crate::config::Config;
+use crate::identity::Identity;
+use crate::network::channel::ChannelKey;
+use crate::network::interfaces;
+use crate::network::mdns::MdnsDiscovery;
 use crate::message::{Message, MessageHandler};
 use anyhow::{Context, Result};
-use local_ip_address::local_ip;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use tokio::net::UdpSocket;
 
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::task::JoinSet;
 use tokio::time::{interval, Duration};
 use tracing::{debug, error, info, warn};
-use uuid::Uuid;
 
 pub struct DiscoveryService {
     config: Config,
-    socket: UdpSocket,
+    sockets: SendSockets,
     message_handler: MessageHandler,
-    peer_id: Uuid,
+    identity: Arc<Identity>,
+    channel: Option<String>,
     tcp_port: u16,
 }
 
+// The sockets announcements go out on: IPv4 always, IPv6 when it is enabled
+// and the host has it. Replies to them come back to the same sockets.
+struct SendSockets {
+    v4: Arc<UdpSocket>,
+    v6: Option<Arc<UdpSocket>>,
+}
+
 impl DiscoveryService {
     pub async fn new(
         config: Config,
         event_sender: mpsc::UnboundedSender<crate::message::ChatEvent>,
         tcp_port: u16,
+        identity: Arc<Identity>,
+        channel_key: Arc<ChannelKey>,
     ) -> Result<Self> {
         // Use any available port for listening, but still broadcast to the standard port
         let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0); // 0 = any available port
@@ -34,98 +48,120 @@ impl DiscoveryService {
         
         socket.set_broadcast(true)
             .context("Failed to enable broadcast on UDP socket")?;
+        if config.discovery_mode.multicast() {
+            socket.set_multicast_ttl_v4(config.multicast_ttl)
+                .context("Failed to set multicast TTL on UDP socket")?;
+        }
         
         let actual_addr = socket.local_addr()?;
         info!("Discovery service listening on {}", actual_addr);
         
+        let socket_v6 = if config.ipv6 {
+            match Self::bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)), false) {
+                Ok(socket) => Some(socket),
+                Err(e) => {
+                    info!("No IPv6 for discovery, announcing over IPv4 only: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        
         let message_handler = MessageHandler::new(
             config.username.clone(),
+            identity.clone(),
             event_sender,
             tcp_port,
-            config.channel.clone(),
+            channel_key.tag(),
         );
-        let peer_id = message_handler.peer_id();
-        
         Ok(Self {
             config,
-            socket,
+            sockets: SendSockets { v4: Arc::new(socket), v6: socket_v6.map(Arc::new) },
             message_handler,
-            peer_id,
+            identity,
+            channel: channel_key.tag(),
             tcp_port,
         })
     }
 
-    pub async fn start_discovery(self) -> Result<()> {
+    /// Runs discovery until `shutdown` fires (or a task dies), then tells the
+    /// network we are leaving.
+    pub async fn start_discovery(self, mut shutdown: watch::Receiver<bool>) -> Result<()> {
         info!("Starting peer discovery...");
         
         let config = self.config.clone();
-        let peer_id = self.peer_id;
+        let identity = self.identity.clone();
+        let channel = self.channel.clone();
         let tcp_port = self.tcp_port;
         info!("Discovery service configuration: username={}, tcp_port={}, discovery_port={}", 
               config.username, tcp_port, config.discovery_port);
         
-        // Create a separate socket for listening on the standard discovery port
+        // Create separate sockets for listening on the standard discovery port
+        let message_handler = Arc::new(RwLock::new(self.message_handler));
+        let mut listen_tasks = JoinSet::new();
         let standard_port_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), config.discovery_port);
-        let listen_socket = match UdpSocket::bind(&standard_port_addr).await {
+        match Self::bind_udp(standard_port_addr, true) {
             Ok(socket) => {
                 info!("Listening for discovery messages on standard port {}", config.discovery_port);
-                Some(Arc::new(socket))
+                if config.discovery_mode.multicast() {
+                    Self::join_multicast_group(&socket, &config);
+                }
+                listen_tasks.spawn(Self::listen(Arc::new(socket), message_handler.clone()));
             }
-            Err(_) => {
-                info!("Standard discovery port {} already in use, will only broadcast", config.discovery_port);
-                None
+            Err(e) => {
+                info!("Can't share standard discovery port {}, will only hear replies to our announcements: {}", config.discovery_port, e);
             }
-        };
-        
-        // Use our own socket for broadcasting
-        let broadcast_socket = Arc::new(self.socket);
-        let message_handler = Arc::new(tokio::sync::RwLock::new(self.message_handler));
+        }
+        if self.sockets.v6.is_some() {
+            match Self::bind_udp(SocketAddr::from((Ipv6Addr::UNSPECIFIED, config.discovery_port)), true) {
+                Ok(socket) => {
+                    info!("Listening for IPv6 discovery messages on standard port {}", config.discovery_port);
+                    Self::join_multicast_group_v6(&socket, &config);
+                    listen_tasks.spawn(Self::listen(Arc::new(socket), message_handler.clone()));
+                }
+                Err(e) => {
+                    info!("Can't listen for IPv6 discovery on port {}: {}", config.discovery_port, e);
+                }
+            }
+        }
         
-        // Start listening task (only if we could bind to standard port)
-        let listen_task = if let Some(listen_sock) = listen_socket {
-            let message_handler_clone = message_handler.clone();
-            Some(tokio::spawn(async move {
-                let mut buf = [0u8; 1024];
-                
-                loop {
-                    match listen_sock.recv_from(&mut buf).await {
-                        Ok((len, addr)) => {
-                            let data = &buf[..len];
-                            
-                            if let Ok(message) = serde_json::from_slice::<Message>(data) {
-                                debug!("Received discovery message from {}: {:?}", addr, message);
-                                
-                                // Don't process our own messages
-                                if let Message::Discovery { peer_id: received_peer_id, .. } = &message {
-                                    if *received_peer_id == peer_id {
-                                        continue;
-                                    }
-                                }
-                                
-                                if let Err(e) = message_handler_clone.write().await.handle_message(message, addr.ip()) {
-                                    warn!("Failed to handle discovery message: {}", e);
-                                }
-                            } else {
-                                debug!("Received invalid discovery message from {}", addr);
-                            }
-                        }
-                        Err(e) => {
-                            error!("Failed to receive discovery message: {}", e);
-                        }
-                    }
+        let mdns = if config.mdns {
+            match Self::start_mdns(&config, &identity, &channel, tcp_port) {
+                Ok((mdns, events)) => {
+                    listen_tasks.spawn(MdnsDiscovery::listen(events, message_handler.clone(), config.ipv6));
+                    Some(mdns)
                 }
-            }))
+                Err(e) => {
+                    warn!("mDNS discovery unavailable: {}", e);
+                    None
+                }
+            }
         } else {
             None
         };
         
+        // Peers answer our announcements straight to the socket they came
+        // from, so listen there too; this is also how we find peers when
+        // another instance holds the standard port
+        listen_tasks.spawn(Self::listen(self.sockets.v4.clone(), message_handler.clone()));
+        if let Some(socket) = &self.sockets.v6 {
+            listen_tasks.spawn(Self::listen(socket.clone(), message_handler.clone()));
+        }
+        
+        // Use our own sockets for broadcasting
+        let broadcast_socket = Arc::new(self.sockets);
+        
         // Start broadcasting task
         let broadcast_config = config.clone();
         let broadcast_tcp_port = tcp_port;
+        let leave_socket = broadcast_socket.clone();
+        let expiry_handler = message_handler.clone();
+        let peer_max_age = chrono::Duration::seconds(config.network_timeout as i64);
         let broadcast_task = tokio::spawn(async move {
             // Send initial broadcast immediately
             info!("Sending initial discovery broadcast...");
-            if let Err(e) = Self::send_discovery_broadcast_static(&broadcast_socket, &broadcast_config, peer_id, broadcast_tcp_port).await {
+            if let Err(e) = Self::send_discovery_broadcast_static(&broadcast_socket, &broadcast_config, &identity, &channel, broadcast_tcp_port).await {
                 warn!("Failed to send initial discovery broadcast: {}", e);
             }
             
@@ -136,49 +172,165 @@ impl DiscoveryService {
                 interval.tick().await;
                 
                 info!("Sending periodic discovery broadcast...");
-                if let Err(e) = Self::send_discovery_broadcast_static(&broadcast_socket, &broadcast_config, peer_id, broadcast_tcp_port).await {
+                if let Err(e) = Self::send_discovery_broadcast_static(&broadcast_socket, &broadcast_config, &identity, &channel, broadcast_tcp_port).await {
                     warn!("Failed to send discovery broadcast: {}", e);
                 }
+                
+                // Peers broadcast as often as we do, so anyone silent for a
+                // whole network_timeout has gone away
+                expiry_handler.write().await.expire_peers(peer_max_age);
             }
         });
         
         // Run tasks concurrently
-        if let Some(listen_task) = listen_task {
-            info!("Starting both listen and broadcast tasks...");
-            tokio::select! {
-                result = listen_task => {
-                    error!("Discovery listening task ended: {:?}", result);
-                }
-                result = broadcast_task => {
-                    error!("Discovery broadcast task ended: {:?}", result);
-                }
+        let mut broadcast_task = broadcast_task;
+        info!("Starting {} listen tasks and the broadcast task...", listen_tasks.len());
+        let shutting_down = tokio::select! {
+            Some(result) = listen_tasks.join_next() => {
+                error!("Discovery listening task ended: {:?}", result);
+                false
             }
-        } else {
-            info!("Starting broadcast-only task...");
-            // Only run broadcast task
-            if let Err(e) = broadcast_task.await {
-                error!("Discovery broadcast task panicked: {:?}", e);
+            result = &mut broadcast_task => {
+                error!("Discovery broadcast task ended: {:?}", result);
+                false
+            }
+            _ = shutdown.changed() => true,
+        };
+        
+        broadcast_task.abort();
+        listen_tasks.abort_all();
+        if let Some(mdns) = mdns {
+            mdns.stop();
+        }
+        
+        if shutting_down {
+            // Peers we never opened a TCP session with only know us from
+            // discovery, so say goodbye there too
+            info!("Broadcasting departure...");
+            let leave = Message::signed_user_leave(config.username.clone(), self.channel.clone(), &self.identity);
+            if let Err(e) = Self::send_broadcast_static(&leave_socket, &config, &leave).await {
+                warn!("Failed to broadcast departure: {}", e);
             }
         }
         
         Ok(())
     }
 
+    // Hands every discovery message that arrives on `socket` to the handler,
+    // and sends its reply, if any, back to where the message came from.
+    async fn listen(socket: Arc<UdpSocket>, message_handler: Arc<RwLock<MessageHandler>>) {
+        let mut buf = [0u8; 1024];
+        
+        loop {
+            match socket.recv_from(&mut buf).await {
+                Ok((len, addr)) => {
+                    let data = &buf[..len];
+                    
+                    if let Ok(message) = serde_json::from_slice::<Message>(data) {
+                        debug!("Received discovery message from {}: {:?}", addr, message);
+                        
+                        let reply = match message_handler.write().await.handle_message(message, addr) {
+                            Ok(reply) => reply,
+                            Err(e) => {
+                                warn!("Failed to handle discovery message: {}", e);
+                                None
+                            }
+                        };
+                        if let Some(reply) = reply {
+                            Self::send_reply(&socket, &reply, addr).await;
+                        }
+                    } else {
+                        debug!("Received invalid discovery message from {}", addr);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to receive discovery message: {}", e);
+                }
+            }
+        }
+    }
+
+    fn start_mdns(
+        config: &Config,
+        identity: &Identity,
+        channel: &Option<String>,
+        tcp_port: u16,
+    ) -> Result<(MdnsDiscovery, mdns_sd::Receiver<mdns_sd::ServiceEvent>)> {
+        let mdns = MdnsDiscovery::start(config, identity, channel, tcp_port)?;
+        let events = mdns.browse()?;
+        Ok((mdns, events))
+    }
+
+    async fn send_reply(socket: &UdpSocket, reply: &Message, addr: SocketAddr) {
+        let data = match serde_json::to_vec(reply) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!("Failed to serialize discovery reply: {}", e);
+                return;
+            }
+        };
+        match socket.send_to(&data, addr).await {
+            Ok(_) => debug!("Sent discovery response to {}", addr),
+            Err(e) => warn!("Failed to send discovery response to {}: {}", addr, e),
+        }
+    }
 
+    // A UDP socket on `addr`; IPv6 ones are IPv6-only, so they don't claim
+    // the IPv4 port. A `shared` socket lets other instances on this machine
+    // bind the same port, and every one of them gets each broadcast and
+    // multicast announcement.
+    fn bind_udp(addr: SocketAddr, shared: bool) -> std::io::Result<UdpSocket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        if shared {
+            socket.set_reuse_address(true)?;
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+        }
+        socket.bind(&addr.into())?;
+        socket.set_nonblocking(true)?;
+        UdpSocket::from_std(socket.into())
+    }
 
-    async fn send_discovery_broadcast_static(socket: &Arc<UdpSocket>, config: &Config, peer_id: Uuid, tcp_port: u16) -> Result<()> {
+    async fn send_discovery_broadcast_static(
+        sockets: &SendSockets,
+        config: &Config,
+        identity: &Identity,
+        channel: &Option<String>,
+        tcp_port: u16,
+    ) -> Result<()> {
         let message = Message::discovery(
             config.username.clone(),
             tcp_port, // Use actual TCP port
-            peer_id,
-            config.channel.clone(),
+            identity.peer_id(),
+            identity.public_key(),
+            channel.clone(),
         );
         
-        let data = serde_json::to_vec(&message)
+        Self::send_broadcast_static(sockets, config, &message).await
+    }
+
+    // Sends `message` to the IPv6 group, if we have IPv6, and over IPv4 the
+    // ways `config.discovery_mode` asks for.
+    async fn send_broadcast_static(sockets: &SendSockets, config: &Config, message: &Message) -> Result<()> {
+        let data = serde_json::to_vec(message)
             .context("Failed to serialize discovery message")?;
         
+        if let Some(socket) = &sockets.v6 {
+            Self::send_multicast_v6_static(socket, config, &data).await;
+        }
+        let socket = &sockets.v4;
+        if config.discovery_mode.multicast() {
+            Self::send_multicast_static(socket, config, &data).await;
+        }
+        if !config.discovery_mode.broadcast() {
+            return Ok(());
+        }
+        
         // Get local network addresses to broadcast to
-        let broadcast_addrs = Self::get_broadcast_addresses_static()?;
+        let broadcast_addrs = Self::get_broadcast_addresses_static(config);
         info!("Broadcasting discovery message to {} addresses", broadcast_addrs.len());
         
         for addr in broadcast_addrs {
@@ -198,57 +350,114 @@ impl DiscoveryService {
         Ok(())
     }
 
-    fn get_broadcast_addresses_static() -> Result<Vec<IpAddr>> {
-        let mut broadcast_addrs = Vec::new();
-        
-        // Get local IP address
-        match local_ip() {
-            Ok(local_ip) => {
-                info!("Local IP detected: {}", local_ip);
-                match local_ip {
-                    IpAddr::V4(ipv4) => {
-                        // Generate broadcast address for common private network ranges
-                        let octets = ipv4.octets();
-                        
-                        // For 192.168.x.x networks
-                        if octets[0] == 192 && octets[1] == 168 {
-                            let broadcast = Ipv4Addr::new(192, 168, octets[2], 255);
-                            broadcast_addrs.push(IpAddr::V4(broadcast));
-                        }
-                        // For 10.x.x.x networks
-                        else if octets[0] == 10 {
-                            let broadcast = Ipv4Addr::new(10, octets[1], octets[2], 255);
-                            info!("Adding 10.x.x.x broadcast address: {}", broadcast);
-                            broadcast_addrs.push(IpAddr::V4(broadcast));
-                        }
-                        // For 172.16-31.x.x networks
-                        else if octets[0] == 172 && octets[1] >= 16 && octets[1] <= 31 {
-                            let broadcast = Ipv4Addr::new(172, octets[1], octets[2], 255);
-                            broadcast_addrs.push(IpAddr::V4(broadcast));
-                        }
-                        
-                        info!("Local IP: {}, broadcasting to: {:?}", local_ip, broadcast_addrs);
-                    }
-                    IpAddr::V6(_) => {
-                        warn!("IPv6 not supported for broadcast discovery");
-                    }
+    // Sends `data` to the multicast group once per interface, since the
+    // kernel would otherwise only use the one the default route points at.
+    async fn send_multicast_static(socket: &UdpSocket, config: &Config, data: &[u8]) {
+        let target = SocketAddr::new(IpAddr::V4(config.multicast_group), config.discovery_port);
+        for interface in Self::multicast_interfaces(config) {
+            if let Err(e) = SockRef::from(socket).set_multicast_if_v4(&interface) {
+                warn!("Can't send multicast discovery from {}: {}", interface, e);
+                continue;
+            }
+            match socket.send_to(data, target).await {
+                Ok(bytes_sent) => {
+                    info!("Sent discovery multicast to {} via {} ({} bytes)", target, interface, bytes_sent);
+                }
+                Err(e) => {
+                    warn!("Failed to send discovery multicast via {}: {}", interface, e);
                 }
             }
-            Err(e) => {
-                warn!("Failed to get local IP: {}", e);
-                // Fallback to common broadcast addresses
-                broadcast_addrs.push(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 255)));
-                broadcast_addrs.push(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 255)));
+        }
+    }
+
+    // The IPv6 group is link-local, so it has to be sent to on each
+    // interface separately.
+    async fn send_multicast_v6_static(socket: &UdpSocket, config: &Config, data: &[u8]) {
+        let target = SocketAddr::new(IpAddr::V6(config.multicast_group_v6), config.discovery_port);
+        for (name, index) in Self::multicast_interfaces_v6(config) {
+            if let Err(e) = SockRef::from(socket).set_multicast_if_v6(index) {
+                warn!("Can't send IPv6 multicast discovery from {}: {}", name, e);
+                continue;
+            }
+            match socket.send_to(data, target).await {
+                Ok(bytes_sent) => {
+                    info!("Sent discovery multicast to {} via {} ({} bytes)", target, name, bytes_sent);
+                }
+                Err(e) => {
+                    warn!("Failed to send discovery multicast via {}: {}", name, e);
+                }
             }
         }
-        
-        // Add limited broadcast as fallback
-        broadcast_addrs.push(IpAddr::V4(Ipv4Addr::BROADCAST));
-        
-        Ok(broadcast_addrs)
     }
 
-    pub fn get_peers(&self) -> &std::collections::HashMap<uuid::Uuid, crate::message::Peer> {
-        self.message_handler.peers()
+    // Joins the discovery group on every interface we can multicast on.
+    fn join_multicast_group(socket: &UdpSocket, config: &Config) {
+        let group = config.multicast_group;
+        for interface in Self::multicast_interfaces(config) {
+            match socket.join_multicast_v4(group, interface) {
+                Ok(()) => info!("Joined discovery group {} on {}", group, interface),
+                Err(e) => warn!("Failed to join discovery group {} on {}: {}", group, interface, e),
+            }
+        }
+    }
+
+    fn join_multicast_group_v6(socket: &UdpSocket, config: &Config) {
+        let group = config.multicast_group_v6;
+        for (name, index) in Self::multicast_interfaces_v6(config) {
+            match socket.join_multicast_v6(&group, index) {
+                Ok(()) => info!("Joined discovery group {} on {}", group, name),
+                Err(e) => warn!("Failed to join discovery group {} on {}: {}", group, name, e),
+            }
+        }
+    }
+
+    // Names and indexes of the IPv6 interfaces to multicast on; index 0
+    // leaves the choice to the kernel when there is nothing else.
+    fn multicast_interfaces_v6(config: &Config) -> Vec<(String, u32)> {
+        let mut interfaces: Vec<(String, u32)> = interfaces::ipv6_interfaces(config)
+            .into_iter()
+            .map(|interface| (interface.name, interface.index))
+            .collect();
+        if interfaces.is_empty() {
+            interfaces.push(("default interface".to_string(), 0));
+        }
+        interfaces
+    }
+
+    // Addresses of the IPv4 interfaces to multicast on; loopback only
+    // reaches ourselves, so it is used only if there is nothing else.
+    fn multicast_interfaces(config: &Config) -> Vec<Ipv4Addr> {
+        let mut interfaces: Vec<Ipv4Addr> = interfaces::ipv4_interfaces(config)
+            .into_iter()
+            .map(|interface| interface.ip)
+            .collect();
+        if interfaces.is_empty() {
+            interfaces.push(Ipv4Addr::LOCALHOST);
+        }
+        interfaces
+    }
+
+    // The subnet broadcast address of every interface we announce on. The
+    // limited broadcast address only leaves through the default route, so
+    // it is a fallback for when no interface qualifies.
+    fn get_broadcast_addresses_static(config: &Config) -> Vec<IpAddr> {
+        let mut broadcast_addrs = Vec::new();
+        for interface in interfaces::ipv4_interfaces(config) {
+            let Some(broadcast) = interface.broadcast() else {
+                debug!("Interface {} ({}) has no broadcast address", interface.name, interface.ip);
+                continue;
+            };
+            info!("Interface {}: {}/{}, broadcasting to {}", interface.name, interface.ip, interface.netmask, broadcast);
+            let broadcast = IpAddr::V4(broadcast);
+            if !broadcast_addrs.contains(&broadcast) {
+                broadcast_addrs.push(broadcast);
+            }
+        }
+        
+        if broadcast_addrs.is_empty() {
+            warn!("No usable network interface found; falling back to {}", Ipv4Addr::BROADCAST);
+            broadcast_addrs.push(IpAddr::V4(Ipv4Addr::BROADCAST));
+        }
+        broadcast_addrs
     }
 }
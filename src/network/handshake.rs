@@ -0,0 +1,64 @@
+use crate::identity;
+use crate::message::types::{Capabilities, PROTOCOL_VERSION_MAX, PROTOCOL_VERSION_MIN};
+use crate::message::Message;
+
+/// Outcome of the `UserJoin` exchange, stored on each `PeerConnection`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Session {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+/// Picks the highest protocol version both sides support and the features
+/// both sides advertise. Returns a human-readable reason on failure, suitable
+/// for a `JoinRejected` message.
+pub fn negotiate(join: &Message, our_channel: &Option<String>, handshake_hash: &[u8]) -> Result<Session, String> {
+    let Message::UserJoin {
+        username,
+        peer_id,
+        public_key,
+        channel,
+        min_version,
+        max_version,
+        capabilities,
+        session_signature,
+        ..
+    } = join else {
+        return Err("expected user_join as the first message".to_string());
+    };
+
+    // The username is pinned as one line of known_peers, so it must not be
+    // able to smuggle in a line break or change under trimming
+    if !valid_username(username) {
+        return Err(format!("invalid username {:?}", username));
+    }
+
+    if channel != our_channel {
+        return Err(format!("channel mismatch for {}", username));
+    }
+
+    if let Err(e) = identity::verify_peer_id(peer_id, public_key) {
+        return Err(format!("invalid identity for {}: {}", username, e));
+    }
+
+    if let Err(e) = identity::verify_signature(public_key, handshake_hash, session_signature) {
+        return Err(format!("{} failed to prove its identity for this session: {}", username, e));
+    }
+
+    let version = PROTOCOL_VERSION_MAX.min(*max_version);
+    if version < PROTOCOL_VERSION_MIN.max(*min_version) {
+        return Err(format!(
+            "no common protocol version (we support {}-{}, {} supports {}-{})",
+            PROTOCOL_VERSION_MIN, PROTOCOL_VERSION_MAX, username, min_version, max_version
+        ));
+    }
+
+    Ok(Session {
+        version,
+        capabilities: Capabilities::supported().intersection(*capabilities),
+    })
+}
+
+pub fn valid_username(username: &str) -> bool {
+    !username.is_empty() && username.trim() == username && !username.chars().any(char::is_control)
+}
@@ -0,0 +1,125 @@
+use crate::config::Config;
+use if_addrs::{get_if_addrs, IfAddr};
+use std::net::{IpAddr, Ipv4Addr};
+use tracing::{debug, warn};
+
+/// An IPv4 address on one of our network interfaces.
+#[derive(Debug, Clone)]
+pub struct LocalInterface {
+    pub name: String,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+}
+
+impl LocalInterface {
+    /// The directed broadcast address of this interface's subnet; None on
+    /// point-to-point links (/31 and /32), which have no broadcast address.
+    pub fn broadcast(&self) -> Option<Ipv4Addr> {
+        let mask = u32::from(self.netmask);
+        if mask.count_ones() >= 31 {
+            return None;
+        }
+        Some(Ipv4Addr::from(u32::from(self.ip) | !mask))
+    }
+}
+
+/// Every IPv4 address on an interface that is up, isn't loopback and passes
+/// the interface filters in `config`.
+pub fn ipv4_interfaces(config: &Config) -> Vec<LocalInterface> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    interfaces
+        .into_iter()
+        .filter(|interface| interface.is_oper_up() && !interface.is_loopback())
+        .filter_map(|interface| {
+            let IfAddr::V4(addr) = interface.addr else {
+                return None;
+            };
+            if !config.uses_interface(&interface.name) {
+                debug!("Skipping interface {} ({}) per config", interface.name, addr.ip);
+                return None;
+            }
+            Some(LocalInterface {
+                name: interface.name,
+                ip: addr.ip,
+                netmask: addr.netmask,
+            })
+        })
+        .collect()
+}
+
+/// An interface that has IPv6, identified by the index IPv6 multicast and
+/// link-local addresses are scoped to.
+#[derive(Debug, Clone)]
+pub struct Ipv6Interface {
+    pub name: String,
+    pub index: u32,
+}
+
+/// Every interface that is up, isn't loopback, passes the interface filters
+/// in `config` and has at least one IPv6 address, listed once each.
+pub fn ipv6_interfaces(config: &Config) -> Vec<Ipv6Interface> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut found: Vec<Ipv6Interface> = Vec::new();
+    for interface in interfaces {
+        if !interface.is_oper_up() || interface.is_loopback() || !matches!(interface.addr, IfAddr::V6(_)) {
+            continue;
+        }
+        let Some(index) = interface.index else {
+            continue;
+        };
+        if found.iter().any(|known| known.index == index) {
+            continue;
+        }
+        if !config.uses_interface(&interface.name) {
+            debug!("Skipping interface {} (IPv6) per config", interface.name);
+            continue;
+        }
+        found.push(Ipv6Interface { name: interface.name, index });
+    }
+    found
+}
+
+/// Names of the interfaces the filters in `config` rule out, for backends
+/// that take a list of interfaces to stay off.
+pub fn excluded_interfaces(config: &Config) -> Vec<String> {
+    let interfaces = match get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut excluded: Vec<String> = Vec::new();
+    for interface in interfaces {
+        if !config.uses_interface(&interface.name) && !excluded.contains(&interface.name) {
+            excluded.push(interface.name);
+        }
+    }
+    excluded
+}
+
+/// Whether `ip` is assigned to one of our interfaces.
+pub fn is_local_address(ip: IpAddr) -> bool {
+    match get_if_addrs() {
+        Ok(interfaces) => interfaces.iter().any(|interface| interface.ip() == ip),
+        Err(e) => {
+            warn!("Failed to list network interfaces: {}", e);
+            false
+        }
+    }
+}
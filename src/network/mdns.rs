@@ -0,0 +1,221 @@
+use crate::config::Config;
+use crate::identity::Identity;
+use crate::message::{Message, MessageHandler};
+use crate::network::interfaces;
+use anyhow::{anyhow, Context, Result};
+use mdns_sd::{IfKind, Receiver, ResolvedService, ScopedIp, ServiceDaemon, ServiceEvent, ServiceInfo};
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV6};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{interval, Duration};
+use tracing::{debug, info, warn};
+use uuid::Uuid;
+
+/// The DNS-SD service type every instance advertises and browses for.
+pub const SERVICE_TYPE: &str = "_localchat._tcp.local.";
+
+/// Advertises us as a `_localchat._tcp` service over mDNS and browses for
+/// other instances, for networks that filter plain UDP broadcast but let
+/// mDNS through.
+pub struct MdnsDiscovery {
+    daemon: ServiceDaemon,
+    fullname: String,
+}
+
+impl MdnsDiscovery {
+    /// Starts the mDNS responder on the interfaces `config` allows and
+    /// registers our service. The instance name is our peer id; the TXT
+    /// record carries everything a `discovery` message would.
+    pub fn start(config: &Config, identity: &Identity, channel: &Option<String>, tcp_port: u16) -> Result<Self> {
+        let daemon = ServiceDaemon::new().map_err(|e| anyhow!("Failed to start mDNS daemon: {}", e))?;
+        for name in interfaces::excluded_interfaces(config) {
+            debug!("Skipping interface {} for mDNS per config", name);
+            let _ = daemon.disable_interface(IfKind::Name(name));
+        }
+        if !config.ipv6 {
+            let _ = daemon.disable_interface(IfKind::IPv6);
+        }
+
+        let peer_id = identity.peer_id().to_string();
+        let tcp_port_text = tcp_port.to_string();
+        let public_key = identity.public_key();
+        let mut properties = vec![
+            ("username", config.username.as_str()),
+            ("peer_id", peer_id.as_str()),
+            ("public_key", public_key.as_str()),
+            ("port", tcp_port_text.as_str()),
+        ];
+        if let Some(channel) = channel {
+            properties.push(("channel", channel.as_str()));
+        }
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &peer_id,
+            &format!("{}.local.", peer_id),
+            (),
+            tcp_port,
+            properties.as_slice(),
+        )
+        .map_err(|e| anyhow!("Invalid mDNS service record: {}", e))?
+        .enable_addr_auto();
+        let fullname = service.get_fullname().to_string();
+        daemon.register(service).map_err(|e| anyhow!("Failed to register mDNS service: {}", e))?;
+        info!("Advertising {} over mDNS", fullname);
+
+        Ok(Self { daemon, fullname })
+    }
+
+    /// Starts browsing for other instances.
+    pub fn browse(&self) -> Result<Receiver<ServiceEvent>> {
+        self.daemon
+            .browse(SERVICE_TYPE)
+            .map_err(|e| anyhow!("Failed to browse for {}: {}", SERVICE_TYPE, e))
+    }
+
+    /// Hands every instance `events` resolves to the handler as a `discovery`
+    /// message, and keeps handing it over as often as UDP peers announce
+    /// themselves until mDNS reports it gone, so it ages out of the peer
+    /// list the same way.
+    pub async fn listen(events: Receiver<ServiceEvent>, message_handler: Arc<RwLock<MessageHandler>>, ipv6: bool) {
+        let mut resolved: HashMap<String, (Message, SocketAddr)> = HashMap::new();
+        let mut refresh = interval(Duration::from_secs(3));
+
+        loop {
+            tokio::select! {
+                event = events.recv_async() => {
+                    let Ok(event) = event else {
+                        warn!("mDNS browsing stopped");
+                        return;
+                    };
+                    match event {
+                        ServiceEvent::ServiceResolved(service) => {
+                            match Self::discovery_from(&service, ipv6) {
+                                Ok((message, addr)) => {
+                                    debug!("Resolved {} at {} over mDNS", service.get_fullname(), addr);
+                                    Self::deliver(&message_handler, message.clone(), addr).await;
+                                    resolved.insert(service.get_fullname().to_string(), (message, addr));
+                                }
+                                Err(e) => debug!("Ignoring mDNS service {}: {}", service.get_fullname(), e),
+                            }
+                        }
+                        ServiceEvent::ServiceRemoved(_, fullname) => {
+                            debug!("mDNS service {} went away", fullname);
+                            resolved.remove(&fullname);
+                        }
+                        _ => {}
+                    }
+                }
+                _ = refresh.tick() => {
+                    for (message, addr) in resolved.values() {
+                        Self::deliver(&message_handler, message.clone(), *addr).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Withdraws our service (peers see it removed) and stops the daemon.
+    pub fn stop(self) {
+        if let Err(e) = self.daemon.unregister(&self.fullname) {
+            warn!("Failed to withdraw mDNS service: {}", e);
+        }
+        if let Err(e) = self.daemon.shutdown() {
+            warn!("Failed to stop mDNS daemon: {}", e);
+        }
+    }
+
+    async fn deliver(message_handler: &RwLock<MessageHandler>, message: Message, addr: SocketAddr) {
+        // Whoever advertised over mDNS finds us the same way, so there is
+        // nothing to reply
+        if let Err(e) = message_handler.write().await.handle_message(message, addr) {
+            warn!("Failed to handle mDNS discovery: {}", e);
+        }
+    }
+
+    // Turns a resolved instance into the `discovery` message it stands for,
+    // plus the address to reach it at: IPv4 if it has one, since that works
+    // without knowing the interface.
+    fn discovery_from(service: &ResolvedService, ipv6: bool) -> Result<(Message, SocketAddr)> {
+        let property = |key: &str| {
+            service
+                .get_property_val_str(key)
+                .ok_or_else(|| anyhow!("TXT record has no {}", key))
+        };
+        let peer_id: Uuid = property("peer_id")?.parse().context("Bad peer_id in TXT record")?;
+        let port: u16 = property("port")?.parse().context("Bad port in TXT record")?;
+        let message = Message::discovery(
+            property("username")?.to_string(),
+            port,
+            peer_id,
+            property("public_key")?.to_string(),
+            service.get_property_val_str("channel").map(str::to_string),
+        );
+
+        let addr = service
+            .get_addresses()
+            .iter()
+            .filter(|ip| ipv6 || ip.is_ipv4())
+            .min_by_key(|ip| (!ip.is_ipv4(), ip.to_ip_addr().is_loopback(), ip.to_ip_addr()))
+            .map(|ip| match ip {
+                ScopedIp::V6(v6) => SocketAddr::V6(SocketAddrV6::new(*v6.addr(), port, 0, v6.scope_id().index)),
+                _ => SocketAddr::new(ip.to_ip_addr(), port),
+            })
+            .ok_or_else(|| anyhow!("no usable address"))?;
+        Ok((message, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use tokio::time::timeout;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn resolved_service_becomes_the_discovery_it_advertises() {
+        let config = Config {
+            include_interfaces: vec!["lo*".to_string()], // "lo" on Linux, "lo0" elsewhere
+            ipv6: false,
+            ..Config::new().with_username("alice".to_string())
+        };
+        let channel = Some("c0ffee".to_string());
+        let alice = Identity::ephemeral();
+        let advertised = MdnsDiscovery::start(&config, &alice, &channel, 8123).unwrap();
+        let browser = MdnsDiscovery::start(&config.clone().with_username("bob".to_string()), &Identity::ephemeral(), &channel, 8124).unwrap();
+
+        let events = browser.browse().unwrap();
+        let alice_name = format!("{}.{}", alice.peer_id(), SERVICE_TYPE);
+        let service = timeout(Duration::from_secs(10), async {
+            loop {
+                if let ServiceEvent::ServiceResolved(service) = events.recv_async().await.unwrap() {
+                    if service.get_fullname() == alice_name {
+                        return service;
+                    }
+                }
+            }
+        })
+        .await
+        .expect("alice was never resolved over loopback");
+
+        let (message, addr) = MdnsDiscovery::discovery_from(&service, false).unwrap();
+        let Message::Discovery { username, port, peer_id, public_key, channel: advertised_channel } = &message else {
+            panic!("expected a discovery, got {:?}", message);
+        };
+        assert_eq!(
+            (username.as_str(), *port, *peer_id, public_key.as_str(), advertised_channel),
+            ("alice", 8123, alice.peer_id(), alice.public_key().as_str(), &channel)
+        );
+        assert_eq!(addr.port(), 8123);
+
+        // The handler takes it like any UDP discovery
+        let (event_sender, mut handled) = mpsc::unbounded_channel();
+        let handler = RwLock::new(MessageHandler::new("bob".to_string(), Arc::new(Identity::ephemeral()), event_sender, 8124, channel.clone()));
+        MdnsDiscovery::deliver(&handler, message, addr).await;
+        let event = handled.try_recv().unwrap();
+        assert_eq!((event.peer.id, event.peer.username.as_str(), event.peer.port), (alice.peer_id(), "alice", 8123));
+
+        advertised.stop();
+        browser.stop();
+    }
+}
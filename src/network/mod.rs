@@ -1,6 +1,15 @@
+pub mod channel;
+pub mod codec;
+pub mod dedup;
 pub mod discovery;
+pub mod handshake;
+pub mod interfaces;
+pub mod mdns;
+pub mod noise;
+pub mod outbox;
 pub mod peer;
 pub mod protocol;
+pub mod reconnect;
 
 pub use discovery::DiscoveryService;
 pub use peer::PeerManager;
@@ -0,0 +1,83 @@
+use super::codec::{FrameReader, FrameWriter};
+use anyhow::{anyhow, Context, Result};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// NN gives us forward-secret encryption from ephemeral keys only. Peers are
+// authenticated afterwards by signing the handshake hash with their Ed25519
+// identity key (see `handshake::negotiate`), which binds the session to that
+// identity without reusing the signing key for Diffie-Hellman. The psk0
+// modifier mixes the channel key in from the first message, so only peers that
+// know the channel passphrase can complete the handshake.
+const NOISE_PARAMS: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+const NOISE_PROLOGUE: &[u8] = b"local-chat";
+
+pub const NOISE_MAX_MESSAGE_LEN: usize = 65535;
+pub const NOISE_TAG_LEN: usize = 16;
+
+/// Keys for an established session. Each direction keeps its own nonce
+/// counter, so the reader and writer halves can share one transport.
+pub struct NoiseSession {
+    pub transport: Arc<StatelessTransportState>,
+    pub handshake_hash: Vec<u8>,
+}
+
+pub async fn initiate<R, W>(reader: &mut FrameReader<R>, writer: &mut FrameWriter<W>, psk: &[u8; 32]) -> Result<NoiseSession>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut state = builder(psk)?.build_initiator()?;
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+
+    // -> psk, e
+    let len = state.write_message(&[], &mut buf)?;
+    writer.write_frame(&buf[..len]).await?;
+
+    // <- e, ee
+    let frame = read_handshake_frame(reader).await?;
+    state.read_message(&frame, &mut buf)
+        .context("Invalid Noise handshake message from responder (wrong channel passphrase?)")?;
+
+    finish(state)
+}
+
+pub async fn respond<R, W>(reader: &mut FrameReader<R>, writer: &mut FrameWriter<W>, psk: &[u8; 32]) -> Result<NoiseSession>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut state = builder(psk)?.build_responder()?;
+    let mut buf = vec![0u8; NOISE_MAX_MESSAGE_LEN];
+
+    // -> psk, e
+    let frame = read_handshake_frame(reader).await?;
+    state.read_message(&frame, &mut buf)
+        .context("Invalid Noise handshake message from initiator (wrong channel passphrase?)")?;
+
+    // <- e, ee
+    let len = state.write_message(&[], &mut buf)?;
+    writer.write_frame(&buf[..len]).await?;
+
+    finish(state)
+}
+
+fn builder(psk: &[u8; 32]) -> Result<Builder<'_>> {
+    let params = NOISE_PARAMS.parse().context("Invalid Noise parameters")?;
+    Ok(Builder::new(params).prologue(NOISE_PROLOGUE).psk(0, psk))
+}
+
+async fn read_handshake_frame<R: AsyncRead + Unpin>(reader: &mut FrameReader<R>) -> Result<Vec<u8>> {
+    reader.read_frame().await?
+        .ok_or_else(|| anyhow!("connection closed during Noise handshake"))
+}
+
+fn finish(state: HandshakeState) -> Result<NoiseSession> {
+    let handshake_hash = state.get_handshake_hash().to_vec();
+    let transport = state.into_stateless_transport_mode()?;
+    Ok(NoiseSession {
+        transport: Arc::new(transport),
+        handshake_hash,
+    })
+}
@@ -0,0 +1,85 @@
+use crate::message::Message;
+use std::collections::{HashMap, VecDeque};
+use tokio::time::{Duration, Instant};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// Messages held for peers whose connection dropped without a goodbye, so a
+/// Wi-Fi roam or a closed laptop lid doesn't lose what was said meanwhile.
+/// Each queue holds at most `max_messages`; messages older than `ttl` are
+/// dropped, and so is the whole queue once the peer has been gone that long.
+pub struct Outbox {
+    queues: HashMap<Uuid, PeerQueue>,
+    max_messages: usize,
+    ttl: Duration,
+}
+
+struct PeerQueue {
+    away_since: Instant,
+    messages: VecDeque<(Instant, Message)>,
+}
+
+impl Outbox {
+    pub fn new(max_messages: usize, ttl: Duration) -> Self {
+        Self {
+            queues: HashMap::new(),
+            max_messages,
+            ttl,
+        }
+    }
+
+    /// Starts holding messages for a peer that just dropped off.
+    pub fn hold_for(&mut self, peer_id: Uuid) {
+        self.queues.entry(peer_id).or_insert_with(|| PeerQueue {
+            away_since: Instant::now(),
+            messages: VecDeque::new(),
+        });
+    }
+
+    /// Stops holding messages for a peer, e.g. because it left for good.
+    pub fn forget(&mut self, peer_id: &Uuid) {
+        self.queues.remove(peer_id);
+    }
+
+    /// Peers we are currently holding messages for.
+    pub fn waiting_peers(&mut self) -> Vec<Uuid> {
+        self.expire();
+        self.queues.keys().copied().collect()
+    }
+
+    /// Queues `message` for `peer_id`. Returns false if we aren't holding
+    /// messages for that peer.
+    pub fn push(&mut self, peer_id: Uuid, message: Message) -> bool {
+        let Some(queue) = self.queues.get_mut(&peer_id) else {
+            return false;
+        };
+        if queue.messages.len() >= self.max_messages {
+            warn!("Outbox for {} is full, dropping its oldest message", peer_id);
+            queue.messages.pop_front();
+        }
+        queue.messages.push_back((Instant::now(), message));
+        true
+    }
+
+    /// Hands back everything held for `peer_id`, oldest first, and stops
+    /// holding messages for it.
+    pub fn take(&mut self, peer_id: &Uuid) -> Vec<Message> {
+        self.expire();
+        let Some(queue) = self.queues.remove(peer_id) else {
+            return Vec::new();
+        };
+        queue.messages.into_iter().map(|(_, message)| message).collect()
+    }
+
+    fn expire(&mut self) {
+        let ttl = self.ttl;
+        self.queues.retain(|peer_id, queue| {
+            if queue.away_since.elapsed() > ttl {
+                debug!("Giving up on {}; dropping {} held messages", peer_id, queue.messages.len());
+                return false;
+            }
+            queue.messages.retain(|(queued_at, _)| queued_at.elapsed() <= ttl);
+            true
+        });
+    }
+}
@@ -1,48 +1,128 @@
-use crate::message::{Message, Peer, ChatEvent};
-use anyhow::{Context, Result};
-use std::collections::HashMap;
-use std::net::SocketAddr;
+use super::channel::ChannelKey;
+use super::codec::{FrameReader, FrameWriter};
+use super::dedup::SeenMessages;
+use super::handshake::{self, Session};
+use super::noise::{self, NoiseSession};
+use super::outbox::Outbox;
+use super::interfaces;
+use super::reconnect::Backoff;
+use crate::config::{Config, KeyChangePolicy};
+use crate::identity::Identity;
+use crate::known_peers::{KnownPeers, TrustDecision};
+use crate::message::{Capabilities, Message, Peer, PeerListEntry, PeerStatus, ChatEvent, SignatureStatus};
+use anyhow::{anyhow, Context, Result};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::io::BufReader;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use tokio::sync::{mpsc, Mutex, RwLock};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::{interval, sleep, timeout, Duration, Instant};
 use tracing::{debug, error, info, warn};
 use uuid::Uuid;
 
+type PeerReader = FrameReader<BufReader<OwnedReadHalf>>;
+type PeerWriter = Arc<RwLock<FrameWriter<OwnedWriteHalf>>>;
+
 pub struct PeerManager {
     listener: TcpListener,
     connections: Arc<RwLock<HashMap<Uuid, PeerConnection>>>,
     event_sender: mpsc::UnboundedSender<ChatEvent>,
     username: String,
-    our_peer_id: Uuid,
-    channel: Option<String>,
+    identity: Arc<Identity>,
+    channel: Option<String>, // opaque channel tag, as it appears on the wire
+    channel_key: Arc<ChannelKey>,
+    max_frame_size: usize,
+    handshake_timeout: Duration,
+    heartbeat_interval: Duration,
+    liveness_timeout: Duration,
+    known_peers: RwLock<KnownPeers>,
+    key_change_policy: KeyChangePolicy,
+    outbox: Mutex<Outbox>, // when taking both, lock this before `connections`
+    seen_messages: Mutex<SeenMessages>,
+    history: Mutex<VecDeque<Message>>, // recent signed group messages, oldest first
+    history_limit: usize,
+    history_requested: Mutex<HashSet<Uuid>>, // peers we asked for history that haven't answered yet
+    relay: bool, // forward group messages on to our other connections
+    pex_interval: Option<Duration>, // None when peer exchange is off
+    discovered: Mutex<HashMap<Uuid, (Peer, Instant)>>, // where and when discovery last saw each peer
+    reconnecting: Mutex<HashSet<Uuid>>, // peers with a retry loop running
+    pinned: Mutex<HashSet<Uuid>>, // peers dialed by address, which discovery never refreshes
+    dialing: Mutex<HashSet<String>>, // addresses with a first dial in progress
+    reconnect_delay: Duration,
+    reconnect_max_delay: Duration,
+    closing: AtomicBool, // set by `shutdown`, so nothing dials out afterwards
 }
 
 struct PeerConnection {
     peer: Peer,
-    writer: Arc<RwLock<tokio::io::WriteHalf<TcpStream>>>,
+    writer: PeerWriter,
+    socket: Socket, // the same socket, for closing it while a write to it is stuck
+    session: Session,
+    heartbeat: JoinHandle<()>,
+    outbound: bool, // we dialed it
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        // Stop pinging as soon as the connection leaves the table
+        self.heartbeat.abort();
+    }
 }
 
+// Only peers that speak v4+ promise to answer heartbeats, so only they can be
+// evicted for going quiet.
+const LIVENESS_MIN_VERSION: u16 = 4;
+
+// Peers on v5+ confirm every chat message with a `message_ack`.
+const RECEIPTS_MIN_VERSION: u16 = 5;
+
+// How many chat message ids we remember for dropping duplicates.
+const SEEN_MESSAGES_CAPACITY: usize = 4096;
+
+// Peers on v6+ answer `history_request`s.
+const HISTORY_MIN_VERSION: u16 = 6;
+
+// Room for the `history_response` envelope around its list of messages.
+const HISTORY_RESPONSE_OVERHEAD: usize = 64;
+
+// Peers on v7+ accept group messages relayed from someone else.
+const RELAY_MIN_VERSION: u16 = 7;
+
+// Peers on v8+ understand a `join_rejected` that closes a duplicate connection.
+const DUPLICATE_NOTICE_MIN_VERSION: u16 = 8;
+
+// Peers on v9+ exchange `peer_list`s.
+const PEX_MIN_VERSION: u16 = 9;
+
+// Most entries sent in, or taken from, one `peer_list`.
+const PEER_LIST_MAX_ENTRIES: usize = 64;
+
+// A connection sending peer lists faster than this gets the extra ones ignored.
+const PEER_LIST_MIN_INTERVAL: Duration = Duration::from_secs(5);
+
 impl PeerManager {
     pub async fn new(
-        port: u16,
+        config: &Config,
         event_sender: mpsc::UnboundedSender<ChatEvent>,
-        username: String,
-        our_peer_id: Uuid,
-        channel: Option<String>,
+        identity: Arc<Identity>,
+        channel_key: Arc<ChannelKey>,
     ) -> Result<Self> {
         // Try the specified port first, then any available port
-        let addr = SocketAddr::from(([0, 0, 0, 0], port));
-        let listener = match TcpListener::bind(&addr).await {
+        let listener = match Self::bind_listener(config.tcp_port_range.0, config.ipv6) {
             Ok(listener) => {
-                info!("Peer manager listening on {}", addr);
+                info!("Peer manager listening on {}", listener.local_addr()?);
                 listener
             }
             Err(_) => {
                 // Port is in use, try any available port
-                let addr = SocketAddr::from(([0, 0, 0, 0], 0));
-                let listener = TcpListener::bind(&addr)
-                    .await
+                let listener = Self::bind_listener(0, config.ipv6)
                     .context("Failed to bind TCP listener to any port")?;
                 
                 let actual_addr = listener.local_addr()?;
@@ -51,45 +131,54 @@ impl PeerManager {
             }
         };
         
+        let known_peers = KnownPeers::load(config.known_peers_path())?;
+        
         Ok(Self {
             listener,
             connections: Arc::new(RwLock::new(HashMap::new())),
             event_sender,
-            username,
-            our_peer_id,
-            channel,
+            username: config.username.clone(),
+            identity,
+            channel: channel_key.tag(),
+            channel_key,
+            max_frame_size: config.max_frame_size,
+            handshake_timeout: Duration::from_secs(config.network_timeout),
+            heartbeat_interval: Duration::from_secs(config.heartbeat_interval.max(1)),
+            liveness_timeout: Duration::from_secs(config.network_timeout),
+            known_peers: RwLock::new(known_peers),
+            key_change_policy: config.key_change_policy,
+            outbox: Mutex::new(Outbox::new(config.outbox_size, Duration::from_secs(config.outbox_ttl))),
+            seen_messages: Mutex::new(SeenMessages::new(SEEN_MESSAGES_CAPACITY)),
+            history: Mutex::new(VecDeque::new()),
+            history_limit: config.history_limit,
+            history_requested: Mutex::new(HashSet::new()),
+            relay: config.relay,
+            pex_interval: (config.pex_interval > 0).then(|| Duration::from_secs(config.pex_interval)),
+            discovered: Mutex::new(HashMap::new()),
+            reconnecting: Mutex::new(HashSet::new()),
+            pinned: Mutex::new(HashSet::new()),
+            dialing: Mutex::new(HashSet::new()),
+            reconnect_delay: Duration::from_secs(config.reconnect_delay.max(1)),
+            reconnect_max_delay: Duration::from_secs(config.reconnect_max_delay.max(1)),
+            closing: AtomicBool::new(false),
         })
     }
 
     pub async fn start(self: Arc<Self>) -> Result<()> {
         info!("Starting peer manager...");
         
-        let connections = self.connections.clone();
-        let event_sender = self.event_sender.clone();
-        let username = self.username.clone();
-        let our_peer_id = self.our_peer_id;
-        let our_channel = self.channel.clone();
+        if let Some(period) = self.pex_interval {
+            tokio::spawn(self.clone().share_peers(period));
+        }
         
         loop {
             match self.listener.accept().await {
                 Ok((stream, addr)) => {
                     info!("New peer connection from {}", addr);
                     
-                    let connections_clone = connections.clone();
-                    let event_sender_clone = event_sender.clone();
-                    let username_clone = username.clone();
-                    
-                    let our_channel_clone = our_channel.clone();
+                    let manager = self.clone();
                     tokio::spawn(async move {
-                        if let Err(e) = Self::handle_incoming_connection(
-                            stream, 
-                            addr, 
-                            connections_clone,
-                            event_sender_clone,
-                            username_clone,
-                            our_peer_id,
-                            our_channel_clone
-                        ).await {
+                        if let Err(e) = manager.handle_incoming_connection(stream, addr).await {
                             error!("Error handling peer connection from {}: {}", addr, e);
                         }
                     });
@@ -101,252 +190,1575 @@ impl PeerManager {
         }
     }
 
-    async fn handle_incoming_connection(
-        stream: TcpStream,
-        addr: SocketAddr,
-        connections: Arc<RwLock<HashMap<Uuid, PeerConnection>>>,
-        event_sender: mpsc::UnboundedSender<ChatEvent>,
-        our_username: String,
-        our_peer_id: Uuid,
-        our_channel: Option<String>,
-    ) -> Result<()> {
-        let (reader, writer) = tokio::io::split(stream);
-        let mut reader = BufReader::new(reader);
-        let writer = Arc::new(RwLock::new(writer));
+    async fn handle_incoming_connection(self: Arc<Self>, stream: TcpStream, addr: SocketAddr) -> Result<()> {
+        let (mut reader, writer) = Self::split_stream(stream, self.max_frame_size);
+        
+        let handshake_hash = self.establish_encryption(&mut reader, &writer, false).await
+            .with_context(|| format!("Noise handshake with {} failed", addr))?;
+        
+        // The dialer speaks first: its UserJoin carries its version range and capabilities
+        let join = self.read_handshake_message(&mut reader).await
+            .with_context(|| format!("Handshake with {} failed", addr))?;
+        
+        let session = match handshake::negotiate(&join, &self.channel, &handshake_hash) {
+            Ok(session) => session,
+            Err(reason) => {
+                info!("Rejecting peer at {}: {}", addr, reason);
+                Self::send_message_to_writer(&writer, &Message::join_rejected(reason)).await?;
+                return Ok(());
+            }
+        };
+        if join.origin_id() == Some(self.identity.peer_id()) {
+            debug!("Rejecting a connection from ourselves at {}", addr);
+            Self::send_message_to_writer(&writer, &Message::join_rejected("connected to itself".to_string())).await?;
+            return Ok(());
+        }
+        
+        let warning = match self.check_trust(&join).await {
+            Ok(warning) => warning,
+            Err(reason) => {
+                warn!("Refusing peer at {}: {}", addr, reason);
+                Self::send_message_to_writer(&writer, &Message::join_rejected(reason)).await?;
+                return Ok(());
+            }
+        };
+        
+        let Message::UserJoin { username, peer_id, .. } = &join else {
+            unreachable!("negotiate only accepts user_join");
+        };
+        let peer = Peer {
+            id: *peer_id,
+            username: username.clone(),
+            // IPv4 clients of the dual-stack listener show up as ::ffff:a.b.c.d
+            ip: addr.ip().to_canonical(),
+            port: addr.port(),
+            last_seen: chrono::Utc::now(),
+            rtt_ms: None,
+            scope_id: Peer::scope_of(&addr),
+        };
+        
+        // Send our own join message back so the dialer can negotiate too
+        let our_join = self.our_join(&handshake_hash);
+        Self::send_message_to_writer(&writer, &our_join).await?;
+        
+        let liveness = self.liveness_for(&session);
+        if self.register_connection(&peer, writer.clone(), session, join, warning, false).await {
+            self.read_messages(reader, writer, peer, liveness).await;
+        }
+        Ok(())
+    }
+    
+    // Runs the Noise handshake and switches both halves of the stream over to
+    // encrypted frames. Returns the handshake hash that each side then signs
+    // with its identity key in its UserJoin.
+    async fn establish_encryption(&self, reader: &mut PeerReader, writer: &PeerWriter, initiator: bool) -> Result<Vec<u8>> {
+        let mut writer = writer.write().await;
+        let handshake = async {
+            if initiator {
+                noise::initiate(reader, &mut writer, self.channel_key.psk()).await
+            } else {
+                noise::respond(reader, &mut writer, self.channel_key.psk()).await
+            }
+        };
+        let NoiseSession { transport, handshake_hash } = timeout(self.handshake_timeout, handshake).await
+            .map_err(|_| anyhow!("timed out waiting for Noise handshake"))??;
+        
+        reader.set_transport(transport.clone());
+        writer.set_transport(transport);
+        Ok(handshake_hash)
+    }
+    
+    async fn read_handshake_message(&self, reader: &mut PeerReader) -> Result<Message> {
+        match timeout(self.handshake_timeout, reader.read_message()).await {
+            Ok(Ok(Some(message))) => Ok(message),
+            Ok(Ok(None)) => Err(anyhow!("connection closed during handshake")),
+            Ok(Err(e)) => Err(e.into()),
+            Err(_) => Err(anyhow!("timed out waiting for handshake")),
+        }
+    }
+    
+    // Trust-on-first-use check of the peer's identity key against known_peers.
+    // Ok(Some(_)) means the key changed but policy lets the peer in anyway.
+    async fn check_trust(&self, join: &Message) -> Result<Option<String>, String> {
+        let Message::UserJoin { username, public_key, .. } = join else {
+            return Err("expected user_join".to_string());
+        };
+        
+        let decision = self.known_peers.write().await.check(username, public_key)
+            .map_err(|e| format!("could not check key for {}: {}", username, e))?;
+        match decision {
+            TrustDecision::New => {
+                info!("First contact with {}; key pinned in known_peers", username);
+                Ok(None)
+            }
+            TrustDecision::Known => Ok(None),
+            TrustDecision::Changed { pinned, presented } => {
+                let message = format!(
+                    "IDENTITY KEY CHANGED for {}: expected {}, got {}",
+                    username, pinned, presented
+                );
+                match self.key_change_policy {
+                    KeyChangePolicy::Refuse => Err(message),
+                    KeyChangePolicy::Warn => {
+                        warn!("{}", message);
+                        Ok(Some(message))
+                    }
+                }
+            }
+        }
+    }
+    
+    // Adds a freshly handshaken connection to the table. Returns false if it
+    // lost to an existing connection with the same peer and was closed.
+    async fn register_connection(&self, peer: &Peer, writer: PeerWriter, session: Session, join: Message, warning: Option<String>, outbound: bool) -> bool {
+        info!(
+            "Handshake with {} complete (protocol v{}, capabilities: [{}])",
+            peer.username,
+            session.version,
+            session.capabilities.names().join(", ")
+        );
+        
+        // Tell the peer who else we know, before it is one of them
+        let peer_list = match self.pex_interval {
+            Some(_) if session.version >= PEX_MIN_VERSION => self.peer_list_for(&peer.id).await,
+            _ => Vec::new(),
+        };
+        
+        // Every insert happens under the outbox lock, so nothing can slip in
+        // between the duplicate check and the insert below
+        let mut outbox = self.outbox.lock().await;
+        let keep_existing = self.connections.read().await.get(&peer.id)
+            .is_some_and(|existing| !self.replaces(existing, outbound));
+        if keep_existing {
+            drop(outbox);
+            info!("Already connected to {}; closing the duplicate connection", peer.username);
+            Self::close_duplicate(&writer, session.version, peer).await;
+            return false;
+        }
+        
+        let socket = match SockRef::from(writer.read().await.get_ref().as_ref()).try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                drop(outbox);
+                warn!("Dropping connection to {}: {}", peer.username, e);
+                return false;
+            }
+        };
+        
+        // Replay what the peer missed while it was away before anything new
+        // can reach it, so it sees messages in order. The writer is locked
+        // before the connection goes into the table, so whatever is sent to
+        // it meanwhile waits behind the replay, without holding up the tables.
+        let held = outbox.take(&peer.id);
+        let mut writing = writer.clone().write_owned().await;
+        let heartbeat = tokio::spawn(Self::send_heartbeats(writer.clone(), peer.clone(), self.identity.peer_id(), self.heartbeat_interval));
+        let connection = PeerConnection {
+            peer: peer.clone(),
+            writer: writer.clone(),
+            socket,
+            session,
+            heartbeat,
+            outbound,
+        };
+        let replaced = self.connections.write().await.insert(peer.id, connection);
+        drop(outbox);
         
-        let mut line = String::new();
-        let mut peer_info: Option<Peer> = None;
+        if !held.is_empty() {
+            info!("Delivering {} held messages to {}", held.len(), peer.username);
+        }
+        let held_count = held.len();
+        let mut greeting = held;
+        // Catch up on what was said before we got here
+        if session.version >= HISTORY_MIN_VERSION && self.history_limit > 0 {
+            self.history_requested.lock().await.insert(peer.id);
+            greeting.push(Message::history_request(self.history_limit, self.channel.clone()));
+        }
+        if !peer_list.is_empty() {
+            greeting.push(Message::peer_list(peer_list, self.channel.clone()));
+        }
+        let mut written = 0;
+        let sent = timeout(self.liveness_timeout, async {
+            for message in &greeting {
+                writing.write_message(message).await?;
+                written += 1;
+            }
+            Ok(())
+        })
+        .await
+        .unwrap_or_else(|_| Err(anyhow!("peer stopped reading; write timed out after {}s", self.liveness_timeout.as_secs())));
+        drop(writing);
+        if let Err(e) = sent {
+            // Whatever of the replay didn't make it is held again
+            let unsent = greeting.into_iter().take(held_count).skip(written).collect();
+            self.evict(peer, &writer, &e, unsent).await;
+        }
+        
+        // The peer is already in the UI; just retire the old connection
+        if let Some(old) = replaced {
+            info!("Replacing the earlier connection to {}", peer.username);
+            Self::close_duplicate(&old.writer, old.session.version, peer).await;
+            return true;
+        }
+        
+        let mut event = ChatEvent::new(peer.clone(), join);
+        if let Some(warning) = warning {
+            event = event.with_warning(warning);
+        }
+        if let Err(e) = self.event_sender.send(event) {
+            error!("Failed to send user join event: {}", e);
+        }
+        true
+    }
+    
+    // Whether a new connection should take the place of `existing`. When two
+    // peers dial each other at the same time, both sides keep the connection
+    // dialed by the peer with the lower id; otherwise the newer one wins, as
+    // the old one is likely dead and not yet noticed.
+    fn replaces(&self, existing: &PeerConnection, outbound: bool) -> bool {
+        if existing.outbound == outbound {
+            return true;
+        }
+        // True if the new connection was dialed by the lower id
+        outbound == (self.identity.peer_id() < existing.peer.id)
+    }
+    
+    // Closes a connection that lost to another one with the same peer. v8+
+    // peers are told first, so they don't mistake it for the peer leaving.
+    async fn close_duplicate(writer: &PeerWriter, version: u16, peer: &Peer) {
+        if version >= DUPLICATE_NOTICE_MIN_VERSION {
+            let notice = Message::join_rejected("duplicate connection".to_string());
+            if let Err(e) = Self::send_message_to_writer(writer, &notice).await {
+                debug!("Failed to tell {} about the duplicate connection: {}", peer.username, e);
+            }
+        }
+        if let Err(e) = writer.write().await.shutdown().await {
+            debug!("Failed to close duplicate connection to {}: {}", peer.username, e);
+        }
+    }
+    
+    fn liveness_for(&self, session: &Session) -> Option<Duration> {
+        (session.version >= LIVENESS_MIN_VERSION).then_some(self.liveness_timeout)
+    }
+    
+    async fn send_heartbeats(writer: PeerWriter, peer: Peer, our_id: Uuid, period: Duration) {
+        let mut ticker = interval(period);
+        ticker.tick().await; // the handshake just proved the peer is alive
         
         loop {
-            line.clear();
+            ticker.tick().await;
+            if let Err(e) = Self::send_message_to_writer(&writer, &Message::heartbeat(our_id)).await {
+                debug!("Stopping heartbeats to {}: {}", peer.username, e);
+                break;
+            }
+        }
+    }
+    
+    // `liveness` is how long the peer may stay silent before we give up on it;
+    // None for peers that don't send heartbeats.
+    async fn read_messages(self: Arc<Self>, mut reader: PeerReader, writer: PeerWriter, mut peer: Peer, liveness: Option<Duration>) {
+        let our_id = self.identity.peer_id();
+        let mut said_goodbye = false;
+        let mut superseded = false;
+        let mut last_peer_list: Option<Instant> = None;
+        loop {
+            let next = match liveness {
+                Some(limit) => match timeout(limit, reader.read_message()).await {
+                    Ok(next) => next,
+                    Err(_) => {
+                        warn!("No traffic from {} for {}s, dropping connection", peer.username, limit.as_secs());
+                        break;
+                    }
+                },
+                None => reader.read_message().await,
+            };
             
-            match reader.read_line(&mut line).await {
-                Ok(0) => {
-                    debug!("Peer {} disconnected", addr);
+            match next {
+                Ok(None) => {
+                    debug!("Peer {} disconnected", peer.username);
                     break;
                 }
-                Ok(_) => {
-                    let line = line.trim();
+                Ok(Some(message)) => {
+                    debug!("Received message from {}: {:?}", peer.username, message);
+                    peer.update_last_seen();
+                    
+                    match &message {
+                        Message::Heartbeat { timestamp, .. } => {
+                            let ack = Message::heartbeat_ack(our_id, *timestamp);
+                            if let Err(e) = Self::send_message_to_writer(&writer, &ack).await {
+                                warn!("Failed to acknowledge heartbeat from {}: {}", peer.username, e);
+                            }
+                        }
+                        Message::HeartbeatAck { timestamp, .. } => {
+                            let rtt = (chrono::Utc::now() - *timestamp).num_milliseconds().max(0) as u64;
+                            debug!("Round trip to {}: {}ms", peer.username, rtt);
+                            peer.rtt_ms = Some(rtt);
+                        }
+                        Message::HistoryRequest { limit, channel } => {
+                            self.send_history(&writer, &peer, *limit, channel).await;
+                            continue;
+                        }
+                        Message::HistoryResponse { messages } => {
+                            if self.history_requested.lock().await.remove(&peer.id) {
+                                self.receive_history(&peer, messages).await;
+                            } else {
+                                warn!("Ignoring history from {} that we didn't ask for", peer.username);
+                            }
+                            continue;
+                        }
+                        Message::PeerList { peers, channel } => {
+                            if last_peer_list.is_some_and(|at| at.elapsed() < PEER_LIST_MIN_INTERVAL) {
+                                debug!("Ignoring a peer list from {} that came too soon", peer.username);
+                            } else {
+                                last_peer_list = Some(Instant::now());
+                                self.receive_peer_list(&peer, peers, channel).await;
+                            }
+                            continue;
+                        }
+                        Message::UserLeave { .. } => {
+                            // The peer is shutting down; nothing more will follow
+                            info!("{} is leaving", peer.username);
+                            said_goodbye = true;
+                            if let Err(e) = self.event_sender.send(ChatEvent::new(peer.clone(), message)) {
+                                error!("Failed to send user leave event: {}", e);
+                            }
+                            break;
+                        }
+                        Message::JoinRejected { reason, .. } => {
+                            // After the handshake this means the peer is
+                            // keeping another connection to us instead
+                            debug!("{} closed a connection to us: {}", peer.username, reason);
+                            superseded = true;
+                            break;
+                        }
+                        _ => {}
+                    }
                     
-                    if !line.is_empty() {
-                        match serde_json::from_str::<Message>(line) {
-                            Ok(message) => {
-                                debug!("Received message from {}: {:?}", addr, message);
-                                
-                                match &message {
-                                    Message::UserJoin { username, peer_id, channel, .. } => {
-                                        if &our_channel != channel {
-                                            debug!("Ignoring incoming TCP join from {} due to channel mismatch", username);
-                                            break;
-                                        }
-                                        // Create peer info for this connection
-                                        let peer = Peer {
-                                            id: *peer_id,
-                                            username: username.clone(),
-                                            ip: addr.ip(),
-                                            port: addr.port(),
-                                            last_seen: chrono::Utc::now(),
-                                        };
-                                        
-                                        // Store connection
-                                        let connection = PeerConnection {
-                                            peer: peer.clone(),
-                                            writer: writer.clone(),
-                                        };
-                                        
-                                        connections.write().await.insert(*peer_id, connection);
-                                        peer_info = Some(peer.clone());
-                                        
-                                        // Send join event
-                                        let event = ChatEvent::new(peer, message);
-                                        if let Err(e) = event_sender.send(event) {
-                                            error!("Failed to send user join event: {}", e);
-                                        }
-                                        
-                                        // Send our own join message back
-                                        let our_join = Message::user_join(our_username.clone(), our_peer_id, our_channel.clone());
-                                        Self::send_message_to_writer(&writer, &our_join).await?;
-                                    }
-                                    Message::ChatMessage { channel, .. } => {
-                                        if &our_channel != channel { continue; }
-                                        if let Some(ref peer) = peer_info {
-                                            let event = ChatEvent::new(peer.clone(), message);
-                                            if let Err(e) = event_sender.send(event) {
-                                                error!("Failed to send chat message event: {}", e);
-                                            }
-                                        }
-                                    }
-                                    _ => {
-                                        // Handle other message types
-                                        if let Some(ref peer) = peer_info {
-                                            let event = ChatEvent::new(peer.clone(), message);
-                                            if let Err(e) = event_sender.send(event) {
-                                                error!("Failed to send event: {}", e);
-                                            }
-                                        }
-                                    }
+                    let mut verified = false;
+                    if let Message::ChatMessage { channel, sender, sender_id, message_id, .. } = &message {
+                        if &self.channel != channel { continue; }
+                        if message.recipient_id().is_some_and(|recipient| recipient != our_id) {
+                            warn!("Dropping private message from {} addressed to someone else", peer.username);
+                            continue;
+                        }
+                        match Self::verify_chat_message(&peer, &message) {
+                            Ok(signed) => verified = signed,
+                            Err(reason) => {
+                                warn!("Dropping chat message from {}: {}", peer.username, reason);
+                                continue;
+                            }
+                        }
+                        
+                        let relayed = verified && *sender_id != peer.id;
+                        if relayed {
+                            match self.author_is_known(&message).await {
+                                Ok(known) => verified = known,
+                                Err(reason) => {
+                                    warn!("Dropping message from {} relayed by {}: {}", sender, peer.username, reason);
+                                    continue;
                                 }
                             }
-                            Err(e) => {
-                                warn!("Failed to parse message from {}: {}", addr, e);
+                        } else {
+                            // Confirm receipt; older builds skip the unknown frame
+                            let ack = Message::message_ack(*message_id, our_id);
+                            if let Err(e) = Self::send_message_to_writer(&writer, &ack).await {
+                                warn!("Failed to acknowledge message from {}: {}", peer.username, e);
+                            }
+                        }
+                        
+                        // Outbox replays and relays through several peers
+                        // may repeat what we already have
+                        if !self.seen_messages.lock().await.insert(*message_id) {
+                            debug!("Dropping duplicate message {} from {}", message_id, peer.username);
+                            continue;
+                        }
+                        if verified {
+                            self.remember(&message).await;
+                            if self.relay {
+                                self.forward(&peer, &message).await;
                             }
                         }
                     }
+                    
+                    let event = ChatEvent::new(peer.clone(), message).with_verified(verified);
+                    if let Err(e) = self.event_sender.send(event) {
+                        error!("Failed to send event: {}", e);
+                    }
                 }
                 Err(e) => {
-                    error!("Failed to read from peer {}: {}", addr, e);
+                    error!("Failed to read from peer {}: {}", peer.username, e);
                     break;
                 }
             }
         }
         
-        // Clean up connection when peer disconnects
-        if let Some(peer) = peer_info {
-            connections.write().await.remove(&peer.id);
-            info!("Removed peer {} from connections", peer.username);
+        // Clean up connection when peer disconnects. A peer that vanished
+        // without a goodbye may be back soon, so start holding messages for it;
+        // one that closed a duplicate connection is back as soon as the other
+        // connection registers.
+        {
+            let mut outbox = self.outbox.lock().await;
+            let mut connections = self.connections.write().await;
+            match connections.get(&peer.id) {
+                Some(current) if Arc::ptr_eq(&current.writer, &writer) => {
+                    connections.remove(&peer.id);
+                }
+                // Evicted after a failed write; lost all the same
+                None if !self.closing.load(Ordering::Relaxed) => {}
+                // Replaced by another connection to the same peer, or shut down
+                _ => {
+                    debug!("Closed a connection to {} that is no longer in use", peer.username);
+                    return;
+                }
+            }
+            if said_goodbye {
+                outbox.forget(&peer.id);
+            } else {
+                outbox.hold_for(peer.id);
+            }
         }
+        info!("Removed peer {} from connections", peer.username);
         
-        Ok(())
+        if said_goodbye {
+            self.forget_peer(&peer.id).await;
+            return;
+        }
+        if superseded {
+            return;
+        }
+        
+        // Let the UI know the peer is gone even if it never said goodbye, and
+        // try to get it back if discovery knows where to find it
+        if self.discovered_peer(&peer.id).await.is_some() {
+            self.report_peer_status(&peer, PeerStatus::Reconnecting { attempt: 0 });
+            tokio::spawn(self.clone().reconnect(peer));
+        } else {
+            let leave = Message::user_leave(peer.username.clone(), peer.id, self.channel.clone());
+            if let Err(e) = self.event_sender.send(ChatEvent::new(peer, leave)) {
+                error!("Failed to send user leave event: {}", e);
+            }
+        }
+    }
+    
+    /// Connects to a peer found by discovery, unless we already have a
+    /// session with it or a retry loop is already at it. If the dial fails,
+    /// keeps retrying with backoff for as long as discovery sees the peer.
+    pub async fn connect_or_retry(self: &Arc<Self>, peer: &Peer) {
+        self.discovered.lock().await.insert(peer.id, (peer.clone(), Instant::now()));
+        if self.reconnecting.lock().await.contains(&peer.id) || self.is_connected(&peer.id).await {
+            return;
+        }
+        
+        info!("Attempting to connect to peer: {}", peer.username);
+        if let Err(e) = self.connect_to_peer(peer).await {
+            error!("Failed to connect to peer {}: {}", peer.username, e);
+            tokio::spawn(self.clone().reconnect(peer.clone()));
+        }
+    }
+    
+    /// Dials `target` (`host:port`, from the config or `/connect`), retrying
+    /// with backoff until someone answers there or we shut down. A `/connect`
+    /// also gives up once nobody has answered for `network_timeout`, the same
+    /// time a lost peer gets; a seed peer (`keep_trying`) may simply not be up
+    /// yet. Discovery won't ever refresh a peer found this way, so it is
+    /// pinned: once lost, it is retried until it comes back or says goodbye.
+    /// Fails if we gave up, or `target` is already being dialed or is us.
+    pub async fn connect_to_address(self: Arc<Self>, target: String, keep_trying: bool) -> Result<()> {
+        if !self.dialing.lock().await.insert(target.clone()) {
+            return Err(anyhow!("already dialing {}", target));
+        }
+        
+        let mut backoff = Backoff::new(self.reconnect_delay, self.reconnect_max_delay);
+        let give_up_at = Instant::now() + self.liveness_timeout;
+        let mut attempts = 0;
+        let outcome = loop {
+            if self.closing.load(Ordering::Relaxed) {
+                break Ok(());
+            }
+            attempts += 1;
+            // Resolve afresh each time, since the name may only resolve once
+            // the other end is up
+            match Self::resolve(&target).await {
+                // A seed list shared between machines lists us too
+                Ok(addr) if self.is_own_address(&addr) => {
+                    info!("Not dialing {}: that's us", target);
+                    break Err(anyhow!("{} is our own address", target));
+                }
+                Ok(addr) => {
+                    let peer = Peer {
+                        id: Uuid::nil(),
+                        ip: addr.ip().to_canonical(),
+                        scope_id: Peer::scope_of(&addr),
+                        ..Peer::new(target.clone(), addr.ip(), addr.port())
+                    };
+                    match self.connect_to_peer(&peer).await {
+                        Ok(peer) => {
+                            info!("Reached {} at {}", peer.username, target);
+                            self.pinned.lock().await.insert(peer.id);
+                            self.discovered.lock().await.insert(peer.id, (peer, Instant::now()));
+                            break Ok(());
+                        }
+                        Err(e) => debug!("Failed to reach {}: {}", target, e),
+                    }
+                }
+                Err(e) => debug!("Failed to reach {}: {}", target, e),
+            }
+            
+            let delay = backoff.next_delay();
+            if !keep_trying && Instant::now() + delay > give_up_at {
+                info!("Giving up on {} after {} attempts", target, attempts);
+                break Err(anyhow!("nobody answered after {} attempts", attempts));
+            }
+            sleep(delay).await;
+        };
+        self.dialing.lock().await.remove(&target);
+        outcome
+    }
+    
+    async fn resolve(target: &str) -> Result<SocketAddr> {
+        tokio::net::lookup_host(target).await
+            .with_context(|| format!("Can't resolve {}", target))?
+            .next()
+            .ok_or_else(|| anyhow!("{} has no address", target))
+    }
+    
+    // Whether `addr` is our own listener, on any of our addresses.
+    fn is_own_address(&self, addr: &SocketAddr) -> bool {
+        let Ok(local) = self.listener.local_addr() else {
+            return false;
+        };
+        let ip = addr.ip().to_canonical();
+        addr.port() == local.port()
+            && (ip.is_loopback() || ip.is_unspecified() || interfaces::is_local_address(ip))
+    }
+    
+    /// Stops trying to reach a peer that told us it is leaving.
+    pub async fn forget_peer(&self, peer_id: &Uuid) {
+        self.discovered.lock().await.remove(peer_id);
+    }
+    
+    // Where discovery last saw `peer_id`, unless it has gone quiet for longer
+    // than discovery itself keeps peers around. Pinned peers never go quiet.
+    async fn discovered_peer(&self, peer_id: &Uuid) -> Option<Peer> {
+        let pinned = self.pinned.lock().await.contains(peer_id);
+        let mut discovered = self.discovered.lock().await;
+        let (peer, seen_at) = discovered.get(peer_id)?;
+        if !pinned && seen_at.elapsed() > self.liveness_timeout {
+            discovered.remove(peer_id);
+            return None;
+        }
+        Some(peer.clone())
+    }
+    
+    // Retries a peer we lost until we have a session with it again (dialed
+    // by either side), discovery stops seeing it, or it says goodbye. Boxed,
+    // because dialing spawns `read_messages`, which may spawn this again.
+    fn reconnect(self: Arc<Self>, peer: Peer) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async move {
+            if !self.reconnecting.lock().await.insert(peer.id) {
+                return;
+            }
+            
+            let mut backoff = Backoff::new(self.reconnect_delay, self.reconnect_max_delay);
+            let mut attempt = 0;
+            let reconnected = loop {
+                sleep(backoff.next_delay()).await;
+                if self.closing.load(Ordering::Relaxed) {
+                    self.reconnecting.lock().await.remove(&peer.id);
+                    return;
+                }
+                if self.is_connected(&peer.id).await {
+                    break true;
+                }
+                // Dial wherever discovery saw the peer last; inbound sessions
+                // only tell us its ephemeral port
+                let Some(target) = self.discovered_peer(&peer.id).await else {
+                    break false;
+                };
+            
+                attempt += 1;
+                self.report_peer_status(&peer, PeerStatus::Reconnecting { attempt });
+                match self.connect_to_peer(&target).await {
+                    Ok(_) => break true,
+                    Err(e) => debug!("Reconnect attempt {} to {} failed: {}", attempt, peer.username, e),
+                }
+            };
+            self.reconnecting.lock().await.remove(&peer.id);
+            
+            if reconnected {
+                info!("Reconnected to {}", peer.username);
+            } else {
+                info!("Giving up on {} after {} attempts", peer.username, attempt);
+                self.report_peer_status(&peer, PeerStatus::Offline);
+            }
+        })
+    }
+    
+    // Tells the UI what we're doing about a peer we lost, as a user_leave
+    // that carries the status.
+    fn report_peer_status(&self, peer: &Peer, status: PeerStatus) {
+        let leave = Message::user_leave(peer.username.clone(), peer.id, self.channel.clone());
+        if let Err(e) = self.event_sender.send(ChatEvent::new(peer.clone(), leave).with_status(status)) {
+            error!("Failed to send peer status event: {}", e);
+        }
+    }
+    
+    // Every `period`, tells each v9+ connection who else we are connected to.
+    // New connections get their first list right after the handshake.
+    async fn share_peers(self: Arc<Self>, period: Duration) {
+        let mut ticker = interval(period);
+        ticker.tick().await;
+        
+        loop {
+            ticker.tick().await;
+            let recipients: Vec<(Peer, PeerWriter)> = self.connections.read().await
+                .values()
+                .filter(|connection| connection.session.version >= PEX_MIN_VERSION)
+                .map(|connection| (connection.peer.clone(), connection.writer.clone()))
+                .collect();
+            for (peer, writer) in recipients {
+                let peer_list = self.peer_list_for(&peer.id).await;
+                if peer_list.is_empty() {
+                    continue;
+                }
+                let list = Message::peer_list(peer_list, self.channel.clone());
+                if let Err(e) = Self::send_message_to_writer(&writer, &list).await {
+                    debug!("Failed to send our peer list to {}: {}", peer.username, e);
+                }
+            }
+        }
+    }
+    
+    // The peers we are connected to, other than `recipient`, at the address
+    // we would dial them at. Addresses that only mean something from here,
+    // like loopback or a link-local one tied to our interface, are left out.
+    async fn peer_list_for(&self, recipient: &Uuid) -> Vec<PeerListEntry> {
+        let connected: Vec<Uuid> = self.connections.read().await
+            .keys()
+            .filter(|peer_id| *peer_id != recipient)
+            .copied()
+            .collect();
+        let discovered = self.discovered.lock().await;
+        connected
+            .iter()
+            .filter_map(|peer_id| discovered.get(peer_id))
+            .map(|(peer, _)| peer)
+            .filter(|peer| !peer.ip.is_loopback() && peer.scope_id == 0)
+            .take(PEER_LIST_MAX_ENTRIES)
+            .map(|peer| PeerListEntry {
+                peer_id: peer.id,
+                username: peer.username.clone(),
+                ip: peer.ip,
+                port: peer.port,
+            })
+            .collect()
+    }
+    
+    // Dials the peers in a `peer_list` that we neither have a session with
+    // nor know an address for ourselves. The handshake checks each one is
+    // who the list says it is.
+    async fn receive_peer_list(self: &Arc<Self>, from: &Peer, entries: &[PeerListEntry], channel: &Option<String>) {
+        if &self.channel != channel || self.pex_interval.is_none() {
+            debug!("Ignoring peer list from {}", from.username);
+            return;
+        }
+        
+        let our_id = self.identity.peer_id();
+        for entry in entries.iter().take(PEER_LIST_MAX_ENTRIES) {
+            if entry.peer_id == our_id || entry.peer_id == from.id || entry.peer_id.is_nil() {
+                continue;
+            }
+            if entry.port == 0 || entry.ip.is_unspecified() || entry.ip.is_loopback() || entry.ip.is_multicast() {
+                debug!("Ignoring unusable address {}:{} for {} from {}", entry.ip, entry.port, entry.username, from.username);
+                continue;
+            }
+            if self.is_connected(&entry.peer_id).await || self.discovered_peer(&entry.peer_id).await.is_some() {
+                continue;
+            }
+            
+            info!("Learned about {} at {}:{} from {}", entry.username, entry.ip, entry.port, from.username);
+            let peer = Peer {
+                id: entry.peer_id,
+                ..Peer::new(entry.username.clone(), entry.ip, entry.port)
+            };
+            tokio::spawn(self.clone().connect_learned(peer));
+        }
+    }
+    
+    // Boxed for the same reason as `reconnect`: `read_messages` spawns this.
+    fn connect_learned(self: Arc<Self>, peer: Peer) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(async move { self.connect_or_retry(&peer).await })
+    }
+    
+    // Keeps signed group messages, in timestamp order, so we can pass them on
+    // to peers that join later.
+    async fn remember(&self, message: &Message) {
+        let Message::ChatMessage { timestamp, .. } = message else {
+            return;
+        };
+        if message.recipient_id().is_some() || self.history_limit == 0 {
+            return;
+        }
+        
+        let mut history = self.history.lock().await;
+        let at = history.partition_point(|held| {
+            matches!(held, Message::ChatMessage { timestamp: held_at, .. } if held_at <= timestamp)
+        });
+        history.insert(at, message.clone());
+        if history.len() > self.history_limit {
+            history.pop_front();
+        }
+    }
+    
+    // Answers a `history_request` with our most recent group messages, as
+    // many as were asked for and fit in one frame.
+    async fn send_history(&self, writer: &PeerWriter, peer: &Peer, limit: usize, channel: &Option<String>) {
+        if channel != &self.channel {
+            debug!("Ignoring history request from {} for another channel", peer.username);
+            return;
+        }
+        
+        let messages: Vec<Message> = {
+            let history = self.history.lock().await;
+            let skip = history.len().saturating_sub(limit.min(self.history_limit));
+            history.iter().skip(skip).cloned().collect()
+        };
+        let fits = |messages: &[Message]| {
+            serde_json::to_vec(messages).is_ok_and(|data| data.len() + HISTORY_RESPONSE_OVERHEAD <= self.max_frame_size)
+        };
+        let start = (0..messages.len()).find(|&start| fits(&messages[start..])).unwrap_or(messages.len());
+        
+        debug!("Sending {} earlier messages to {}", messages.len() - start, peer.username);
+        let response = Message::history_response(messages[start..].to_vec());
+        if let Err(e) = Self::send_message_to_writer(writer, &response).await {
+            warn!("Failed to send history to {}: {}", peer.username, e);
+        }
     }
     
-    async fn send_message_to_writer(
-        writer: &Arc<RwLock<tokio::io::WriteHalf<TcpStream>>>,
-        message: &Message,
-    ) -> Result<()> {
-        let data = serde_json::to_string(message)
-            .context("Failed to serialize message")?;
+    // Floods a signed group message on to every v7+ connection other than
+    // the one it came in on and its author's, with one hop less to go.
+    async fn forward(&self, from: &Peer, message: &Message) {
+        let Message::ChatMessage { sender_id, hop_limit, .. } = message else {
+            return;
+        };
+        if *hop_limit <= 1 || message.recipient_id().is_some() {
+            return;
+        }
+        let author = *sender_id;
+        let mut forwarded = message.clone();
+        if let Message::ChatMessage { hop_limit, .. } = &mut forwarded {
+            *hop_limit -= 1;
+        }
         
-        let mut writer_guard = writer.write().await;
-        writer_guard.write_all(data.as_bytes()).await
-            .context("Failed to write message to peer")?;
-        writer_guard.write_all(b"\n").await
-            .context("Failed to write newline to peer")?;
-        writer_guard.flush().await
-            .context("Failed to flush message to peer")?;
+        let targets: Vec<(Peer, PeerWriter)> = self.connections.read().await
+            .iter()
+            .filter(|(peer_id, connection)| {
+                **peer_id != from.id && **peer_id != author && connection.session.version >= RELAY_MIN_VERSION
+            })
+            .map(|(_, connection)| (connection.peer.clone(), connection.writer.clone()))
+            .collect();
+        for (peer, _, sent) in self.send_to_each(targets, &forwarded).await {
+            match sent {
+                Ok(()) => debug!("Relayed message from {} to {}", from.username, peer.username),
+                Err(e) => warn!("Failed to relay message to {}: {}", peer.username, e),
+            }
+        }
+    }
+    
+    // Whether a message we got second hand is signed by an author we know:
+    // with the key pinned for its sender's username, which every session
+    // pins, or by a peer we have a session with now (peer ids are derived
+    // from keys, so the id vouches for the key). Peers only heard of through
+    // someone's peer list don't count. Ok(false) for authors we've never met,
+    // whose messages are shown as unverified and not passed on, since anyone
+    // can sign as a new name. Err if the key contradicts the pinned one.
+    async fn author_is_known(&self, message: &Message) -> Result<bool, String> {
+        let Message::ChatMessage { sender, sender_id, public_key, .. } = message else {
+            return Ok(false);
+        };
+        match self.known_peers.read().await.peek(sender, public_key) {
+            Ok(TrustDecision::Known) => return Ok(true),
+            Ok(TrustDecision::Changed { .. }) => return Err("key doesn't match the pinned one".to_string()),
+            Ok(TrustDecision::New) => {}
+            Err(e) => return Err(format!("can't check its key: {}", e)),
+        }
+        Ok(self.is_connected(sender_id).await)
+    }
+    
+    // Passes on messages from a `history_response` that we haven't seen yet.
+    // They were relayed rather than sent to us, so only ones carrying a valid
+    // signature by their author are accepted, and only ones by authors we
+    // know are marked verified.
+    async fn receive_history(&self, peer: &Peer, messages: &[Message]) {
+        let mut accepted = 0;
+        for message in messages {
+            let Message::ChatMessage { sender, message_id, channel, .. } = message else {
+                continue;
+            };
+            if channel != &self.channel || message.recipient_id().is_some() {
+                continue;
+            }
+            if !matches!(message.verify_signature(), SignatureStatus::Verified) {
+                debug!("Skipping unverifiable history message from {} relayed by {}", sender, peer.username);
+                continue;
+            }
+            let known = match self.author_is_known(message).await {
+                Ok(known) => known,
+                Err(reason) => {
+                    warn!("Skipping history message from {} relayed by {}: {}", sender, peer.username, reason);
+                    continue;
+                }
+            };
+            if !self.seen_messages.lock().await.insert(*message_id) {
+                continue;
+            }
+            
+            if known {
+                self.remember(message).await;
+            }
+            let event = ChatEvent::new(peer.clone(), message.clone())
+                .with_verified(known)
+                .with_backfilled(true);
+            if let Err(e) = self.event_sender.send(event) {
+                error!("Failed to send event: {}", e);
+            }
+            accepted += 1;
+        }
+        info!("Caught up on {} earlier messages from {}", accepted, peer.username);
+    }
+    
+    // Ok(true) if the message is signed by the peer on this connection, or is
+    // a group message it relayed from someone else, Ok(false) if it is
+    // unsigned (and so only vouched for by this connection), Err if it is
+    // forged.
+    fn verify_chat_message(peer: &Peer, message: &Message) -> Result<bool, String> {
+        let Message::ChatMessage { sender, sender_id, .. } = message else {
+            return Ok(false);
+        };
+        match message.verify_signature() {
+            SignatureStatus::Verified if *sender_id != peer.id && message.recipient_id().is_some() => {
+                Err(format!("signed by a different identity ({})", sender_id))
+            }
+            SignatureStatus::Verified if *sender_id == peer.id && *sender != peer.username => {
+                Err(format!("claims to be from {}", sender))
+            }
+            SignatureStatus::Verified => Ok(true),
+            SignatureStatus::Unsigned => Ok(false),
+            SignatureStatus::Invalid(reason) => Err(format!("bad signature: {}", reason)),
+        }
+    }
+    
+    fn split_stream(stream: TcpStream, max_frame_size: usize) -> (PeerReader, PeerWriter) {
+        let (reader, writer) = stream.into_split();
+        let reader = FrameReader::new(BufReader::new(reader), max_frame_size);
+        let writer = Arc::new(RwLock::new(FrameWriter::new(writer, max_frame_size)));
+        (reader, writer)
+    }
+    
+    async fn send_message_to_writer(writer: &PeerWriter, message: &Message) -> Result<()> {
+        writer.write().await.write_message(message).await
+            .context("Failed to send message to peer")?;
         
         debug!("Sent message: {:?}", message);
         Ok(())
     }
 
-    pub async fn send_message_to_peer(&self, peer_id: &Uuid, message: &Message) -> Result<()> {
-        let connections = self.connections.read().await;
-        if let Some(connection) = connections.get(peer_id) {
-            Self::send_message_to_writer(&connection.writer, message).await?;
-            debug!("Sent message to peer {}: {:?}", peer_id, message);
-        } else {
-            warn!("Peer {} not found in connections", peer_id);
+    /// Sends `message` to one peer only. Fails if we have no session with
+    /// that peer, or if it is a private message and the peer's build would
+    /// show it to everyone as a group message. Returns whether the peer will
+    /// send a delivery receipt.
+    pub async fn send_message_to_peer(&self, peer_id: &Uuid, message: &Message) -> Result<bool> {
+        let (peer, writer, acknowledges) = {
+            let connections = self.connections.read().await;
+            let connection = connections.get(peer_id)
+                .ok_or_else(|| anyhow!("peer {} is not connected", peer_id))?;
+            if message.recipient_id().is_some() && !connection.session.capabilities.contains(Capabilities::DIRECT_MESSAGES) {
+                return Err(anyhow!("{} does not support private messages", connection.peer.username));
+            }
+            (connection.peer.clone(), connection.writer.clone(), connection.session.version >= RECEIPTS_MIN_VERSION)
+        };
+        
+        let (_, _, sent) = self.send_to_each(vec![(peer, writer)], message).await
+            .pop()
+            .ok_or_else(|| anyhow!("failed to send message to peer {}", peer_id))?;
+        sent?;
+        debug!("Sent message to peer {}: {:?}", peer_id, message);
+        Ok(acknowledges)
+    }
+    
+    /// Sends `message` to every connected peer, and holds it for peers that
+    /// dropped off recently. Returns the peers that should confirm it with a
+    /// delivery receipt; held-for and unreachable peers are included, since
+    /// the sender should see whether they ever get it.
+    pub async fn broadcast_message(&self, message: &Message) -> Result<Vec<Uuid>> {
+        if message.recipient_id().is_some() {
+            return Err(anyhow!("refusing to broadcast a private message"));
+        }
+        if let Message::ChatMessage { message_id, .. } = message {
+            // Our own messages may come back to us in someone's history
+            self.seen_messages.lock().await.insert(*message_id);
+            self.remember(message).await;
         }
         
-        Ok(())
+        // Hold it for peers that dropped off, and note who to write it to,
+        // under the same locks `register_connection` takes: a peer that
+        // reconnects either is in the snapshot or gets the held copy
+        let mut recipients = Vec::new();
+        let mut acknowledging = HashSet::new();
+        let targets: Vec<(Peer, PeerWriter)> = {
+            let mut outbox = self.outbox.lock().await;
+            let connections = self.connections.read().await;
+            for peer_id in outbox.waiting_peers() {
+                if !connections.contains_key(&peer_id) && outbox.push(peer_id, message.clone()) {
+                    debug!("Holding message for disconnected peer {}", peer_id);
+                    recipients.push(peer_id);
+                }
+            }
+            let mut targets = Vec::new();
+            for (peer_id, connection) in connections.iter() {
+                if connection.session.version >= RECEIPTS_MIN_VERSION {
+                    acknowledging.insert(*peer_id);
+                }
+                targets.push((connection.peer.clone(), connection.writer.clone()));
+            }
+            targets
+        };
+        
+        let mut failed = Vec::new();
+        for (peer, writer, sent) in self.send_to_each(targets, message).await {
+            match sent {
+                Ok(()) if acknowledging.contains(&peer.id) => recipients.push(peer.id),
+                Ok(()) => {}
+                Err(e) => {
+                    error!("Failed to send message to peer {}: {}", peer.id, e);
+                    recipients.push(peer.id);
+                    failed.push((peer, writer));
+                }
+            }
+        }
+        if failed.is_empty() {
+            return Ok(recipients);
+        }
+        
+        // Keep the message for when a dying connection comes back, unless it
+        // already has: then it goes over the new connection
+        let mut retry = Vec::new();
+        {
+            let mut outbox = self.outbox.lock().await;
+            let connections = self.connections.read().await;
+            for (peer, writer) in failed {
+                match connections.get(&peer.id) {
+                    Some(current) if !Arc::ptr_eq(&current.writer, &writer) => retry.push((peer, current.writer.clone())),
+                    _ => {
+                        outbox.hold_for(peer.id);
+                        outbox.push(peer.id, message.clone());
+                    }
+                }
+            }
+        }
+        for (peer, _, sent) in self.send_to_each(retry, message).await {
+            if let Err(e) = sent {
+                error!("Failed to send message to peer {} over its new connection: {}", peer.id, e);
+            }
+        }
+        Ok(recipients)
     }
     
-    pub async fn broadcast_message(&self, message: &Message) -> Result<()> {
-        let connections = self.connections.read().await;
-        for (peer_id, connection) in connections.iter() {
-            if let Err(e) = Self::send_message_to_writer(&connection.writer, message).await {
-                error!("Failed to send message to peer {}: {}", peer_id, e);
+    // Writes `message` to all of `targets` at once, giving up on each after
+    // `liveness_timeout`: a peer that stops reading fills its TCP window and
+    // would otherwise stall the write for good. Connections whose write failed
+    // are evicted. Callers must not hold `outbox` or `connections`, so a
+    // stalled peer holds up nothing else.
+    async fn send_to_each(&self, targets: Vec<(Peer, PeerWriter)>, message: &Message) -> Vec<(Peer, PeerWriter, Result<()>)> {
+        let message = Arc::new(message.clone());
+        let limit = self.liveness_timeout;
+        let mut writes = JoinSet::new();
+        for (peer, writer) in targets {
+            let message = message.clone();
+            writes.spawn(async move {
+                let sent = match timeout(limit, Self::send_message_to_writer(&writer, &message)).await {
+                    Ok(sent) => sent,
+                    Err(_) => Err(anyhow!("peer stopped reading; write timed out after {}s", limit.as_secs())),
+                };
+                (peer, writer, sent)
+            });
+        }
+        
+        let mut results = Vec::new();
+        while let Some(result) = writes.join_next().await {
+            match result {
+                Ok(result) => results.push(result),
+                Err(e) => error!("Write task failed: {}", e),
+            }
+        }
+        for (peer, writer, sent) in &results {
+            if let Err(e) = sent {
+                self.evict(peer, writer, e, Vec::new()).await;
+            }
+        }
+        results
+    }
+    
+    // Takes a connection out of the table after a write to it failed. The
+    // write may have stopped partway through a frame, with its Noise nonce
+    // already spent, so nothing more can be sent on it. Closing the socket
+    // ends its reader too, which reports the peer as lost like any other
+    // dropped connection; meanwhile messages are held for it, starting with
+    // `unsent`.
+    async fn evict(&self, peer: &Peer, writer: &PeerWriter, error: &anyhow::Error, unsent: Vec<Message>) {
+        let evicted = {
+            let mut outbox = self.outbox.lock().await;
+            let mut connections = self.connections.write().await;
+            let current = connections.get(&peer.id).is_some_and(|current| Arc::ptr_eq(&current.writer, writer));
+            if current {
+                outbox.hold_for(peer.id);
+            }
+            for message in unsent {
+                outbox.push(peer.id, message);
+            }
+            if !current {
+                return;
+            }
+            connections.remove(&peer.id)
+        };
+        if let Some(connection) = evicted {
+            warn!("Dropping connection to {}: {:#}", peer.username, error);
+            if let Err(e) = connection.socket.shutdown(std::net::Shutdown::Both) {
+                debug!("Failed to close connection to {}: {}", peer.username, e);
             }
         }
-        Ok(())
     }
 
-    pub async fn connect_to_peer(&self, peer: &Peer) -> Result<()> {
-        let addr = SocketAddr::new(peer.ip, peer.port);
+    // Listens on `port` for both IPv4 and IPv6 peers, or on IPv4 only when
+    // IPv6 is off or the host doesn't have it.
+    fn bind_listener(port: u16, ipv6: bool) -> std::io::Result<TcpListener> {
+        if ipv6 {
+            match Self::bind_dual_stack(port) {
+                Ok(listener) => return Ok(listener),
+                Err(e) => debug!("No dual-stack listener on port {}, using IPv4 only: {}", port, e),
+            }
+        }
+        let listener = std::net::TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port)))?;
+        listener.set_nonblocking(true)?;
+        TcpListener::from_std(listener)
+    }
+    
+    fn bind_dual_stack(port: u16) -> std::io::Result<TcpListener> {
+        let socket = Socket::new(Domain::IPV6, Type::STREAM, Some(Protocol::TCP))?;
+        socket.set_only_v6(false)?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+        socket.listen(1024)?;
+        socket.set_nonblocking(true)?;
+        TcpListener::from_std(socket.into())
+    }
+    
+    /// Dials `peer` and runs the handshake, returning the peer as it
+    /// identified itself. A nil id means we only know the address, and
+    /// accept whoever answers there.
+    pub async fn connect_to_peer(self: &Arc<Self>, peer: &Peer) -> Result<Peer> {
+        let addr = peer.socket_addr();
         
         // Check if already connected
         {
             let connections = self.connections.read().await;
             if connections.contains_key(&peer.id) {
                 debug!("Already connected to peer {}", peer.username);
-                return Ok(());
+                return Ok(peer.clone());
             }
         }
         
-        match TcpStream::connect(&addr).await {
-            Ok(stream) => {
-                info!("Connected to peer {} at {}", peer.username, addr);
-                
-                let (reader, writer) = tokio::io::split(stream);
-                let writer = Arc::new(RwLock::new(writer));
-                
-                let connection = PeerConnection {
-                    peer: peer.clone(),
-                    writer: writer.clone(),
-                };
-                
-                // Store the connection
-                self.connections.write().await.insert(peer.id, connection);
-                
-                // Send user join message to establish the connection
-                let join_message = Message::user_join(self.username.clone(), self.our_peer_id, self.channel.clone());
-                Self::send_message_to_writer(&writer, &join_message).await?;
-                
-                // Start handling messages from this peer
-                let connections_clone = self.connections.clone();
-                let event_sender_clone = self.event_sender.clone();
-                let peer_clone = peer.clone();
-                
-                tokio::spawn(async move {
-                    let mut reader = BufReader::new(reader);
-                    let mut line = String::new();
-                    
-                    loop {
-                        line.clear();
-                        match reader.read_line(&mut line).await {
-                            Ok(0) => {
-                                debug!("Peer {} disconnected", peer_clone.username);
-                                connections_clone.write().await.remove(&peer_clone.id);
-                                break;
-                            }
-                            Ok(_) => {
-                                let line = line.trim();
-                                if !line.is_empty() {
-                                    if let Ok(message) = serde_json::from_str::<Message>(line) {
-                                        debug!("Received message from {}: {:?}", peer_clone.username, message);
-                                        let event = ChatEvent::new(peer_clone.clone(), message);
-                                        if let Err(e) = event_sender_clone.send(event) {
-                                            error!("Failed to send event: {}", e);
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => {
-                                error!("Failed to read from peer {}: {}", peer_clone.username, e);
-                                connections_clone.write().await.remove(&peer_clone.id);
-                                break;
-                            }
-                        }
-                    }
-                });
-                
-                Ok(())
-            }
-            Err(e) => {
+        // A dropped SYN would otherwise wait out the kernel's own connect
+        // timeout, a couple of minutes on Linux
+        let stream = match timeout(self.handshake_timeout, TcpStream::connect(&addr)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => {
                 warn!("Failed to connect to peer {} at {}: {}", peer.username, addr, e);
-                Err(e.into())
+                return Err(e.into());
+            }
+            Err(_) => {
+                warn!("Timed out connecting to peer {} at {}", peer.username, addr);
+                return Err(anyhow!("timed out connecting to {}", addr));
             }
+        };
+        info!("Connected to peer {} at {}", peer.username, addr);
+        
+        let (mut reader, writer) = Self::split_stream(stream, self.max_frame_size);
+        
+        let handshake_hash = self.establish_encryption(&mut reader, &writer, true).await
+            .with_context(|| format!("Noise handshake with {} failed", peer.username))?;
+        
+        // Send user join message to start the handshake
+        let join_message = self.our_join(&handshake_hash);
+        Self::send_message_to_writer(&writer, &join_message).await?;
+        
+        let reply = self.read_handshake_message(&mut reader).await
+            .with_context(|| format!("Handshake with {} failed", peer.username))?;
+        if let Message::JoinRejected { reason, min_version, max_version } = &reply {
+            warn!("Peer {} rejected our join (supports protocol {}-{}): {}", peer.username, min_version, max_version, reason);
+            return Err(anyhow!("rejected by {}: {}", peer.username, reason));
         }
+        let session = handshake::negotiate(&reply, &self.channel, &handshake_hash)
+            .map_err(|reason| anyhow!("Incompatible peer {}: {}", peer.username, reason))?;
+        let Message::UserJoin { peer_id, username, .. } = &reply else {
+            unreachable!("negotiate only accepts user_join");
+        };
+        if !peer.id.is_nil() && *peer_id != peer.id {
+            return Err(anyhow!("{} at {} answered with a different identity ({})", peer.username, addr, peer_id));
+        }
+        let peer = Peer {
+            id: *peer_id,
+            username: username.clone(),
+            ..peer.clone()
+        };
+        let warning = self.check_trust(&reply).await
+            .map_err(|reason| anyhow!("Refusing peer {}: {}", peer.username, reason))?;
+        
+        let liveness = self.liveness_for(&session);
+        if self.register_connection(&peer, writer.clone(), session, reply, warning, true).await {
+            // Start handling messages from this peer
+            tokio::spawn(self.clone().read_messages(reader, writer, peer.clone(), liveness));
+        }
+        
+        Ok(peer)
     }
 
-    pub async fn disconnect_peer(&self, peer_id: &Uuid) {
-        if let Some(_connection) = self.connections.write().await.remove(peer_id) {
-            info!("Disconnected from peer {}", peer_id);
+    /// Tells every connected peer we are leaving and closes the connections,
+    /// all at once, giving up on a peer that hasn't taken the goodbye within
+    /// `limit`. Connections are taken out of the table first, so no new
+    /// messages are sent after the goodbye.
+    pub async fn shutdown(&self, limit: Duration) {
+        self.closing.store(true, Ordering::Relaxed);
+        let leave = Message::user_leave(self.username.clone(), self.identity.peer_id(), self.channel.clone());
+        let connections: Vec<PeerConnection> = self.connections.write().await
+            .drain()
+            .map(|(_, connection)| connection)
+            .collect();
+        info!("Saying goodbye to {} peers", connections.len());
+        
+        let mut goodbyes = JoinSet::new();
+        for connection in connections {
+            connection.heartbeat.abort();
+            let leave = leave.clone();
+            goodbyes.spawn(async move {
+                match timeout(limit, Self::send_message_to_writer(&connection.writer, &leave)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => warn!("Failed to say goodbye to {}: {}", connection.peer.username, e),
+                    Err(_) => warn!("Timed out saying goodbye to {}", connection.peer.username),
+                }
+                // Through the socket rather than the writer, which a stuck
+                // write may still have locked
+                if let Err(e) = connection.socket.shutdown(std::net::Shutdown::Write) {
+                    debug!("Failed to close connection to {}: {}", connection.peer.username, e);
+                }
+            });
         }
+        while goodbyes.join_next().await.is_some() {}
     }
 
     pub async fn is_connected(&self, peer_id: &Uuid) -> bool {
         self.connections.read().await.contains_key(peer_id)
     }
-
-    pub async fn connected_peers(&self) -> Vec<Peer> {
-        self.connections.read().await.values().map(|conn| conn.peer.clone()).collect()
-    }
     
-    pub async fn get_connection_count(&self) -> usize {
-        self.connections.read().await.len()
+    fn our_join(&self, handshake_hash: &[u8]) -> Message {
+        let mut join = Message::user_join(self.username.clone(), self.identity.peer_id(), self.identity.public_key(), self.channel.clone());
+        if let Message::UserJoin { session_signature, .. } = &mut join {
+            *session_signature = self.identity.sign(handshake_hash);
+        }
+        join
     }
     
     pub fn get_tcp_port(&self) -> Result<u16> {
         Ok(self.listener.local_addr()?.port())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::PathBuf;
+
+    struct TestPeer {
+        manager: Arc<PeerManager>,
+        identity: Arc<Identity>,
+        events: mpsc::UnboundedReceiver<ChatEvent>,
+        config_dir: PathBuf,
+    }
+
+    impl TestPeer {
+        async fn start(username: &str, channel_key: ChannelKey) -> Self {
+            Self::start_with(Config::new(), username, channel_key).await
+        }
+
+        async fn start_with(config: Config, username: &str, channel_key: ChannelKey) -> Self {
+            let config_dir = std::env::temp_dir().join(format!("local-chat-{}-{}", username, Uuid::new_v4()));
+            let config = Config {
+                tcp_port_range: (0, 0),
+                ipv6: false,
+                config_dir: config_dir.clone(),
+                ..config.with_username(username.to_string())
+            };
+            let identity = Arc::new(Identity::load_or_generate(&config.identity_path()).unwrap());
+            let (event_sender, events) = mpsc::unbounded_channel();
+            let manager = Arc::new(PeerManager::new(&config, event_sender, identity.clone(), Arc::new(channel_key)).await.unwrap());
+            tokio::spawn(manager.clone().start());
+            Self { manager, identity, events, config_dir }
+        }
+
+        // How the other side would dial us.
+        fn as_peer(&self) -> Peer {
+            Peer {
+                id: self.identity.peer_id(),
+                ..Peer::new(self.manager.username.clone(), IpAddr::V4(Ipv4Addr::LOCALHOST), self.manager.get_tcp_port().unwrap())
+            }
+        }
+
+        // Waits for the first event that `wanted` picks out, skipping others.
+        async fn event(&mut self, wanted: impl Fn(&ChatEvent) -> bool) -> ChatEvent {
+            timeout(Duration::from_secs(5), async {
+                loop {
+                    let event = self.events.recv().await.expect("event channel closed");
+                    if wanted(&event) {
+                        return event;
+                    }
+                }
+            })
+            .await
+            .expect("timed out waiting for an event")
+        }
+    }
+
+    impl Drop for TestPeer {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.config_dir);
+        }
+    }
+
+    fn channel(passphrase: Option<&str>) -> ChannelKey {
+        ChannelKey::derive(Some("test"), passphrase).unwrap()
+    }
+
+    // A connection to a peer that never reads, with its socket buffers full.
+    // The peer's end is returned too, to keep it open.
+    async fn stalled_writer(manager: &PeerManager) -> (PeerWriter, Socket, TcpStream) {
+        let sink = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(sink.local_addr().unwrap()).await.unwrap();
+        let (unread, _) = sink.accept().await.unwrap();
+        let socket = SockRef::from(&stream).try_clone().unwrap();
+        let (_, writer) = PeerManager::split_stream(stream, manager.max_frame_size);
+        let filler = Message::history_response(Vec::new());
+        while timeout(Duration::from_millis(200), PeerManager::send_message_to_writer(&writer, &filler)).await.is_ok() {}
+        (writer, socket, unread)
+    }
+
+    #[tokio::test]
+    async fn loopback_session_joins_and_carries_signed_messages() {
+        let mut alice = TestPeer::start("alice", channel(None)).await;
+        let mut bob = TestPeer::start("bob", channel(None)).await;
+
+        let reached = alice.manager.connect_to_peer(&bob.as_peer()).await.unwrap();
+        assert_eq!(reached.id, bob.identity.peer_id());
+        let join = bob.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+        assert_eq!(join.peer.id, alice.identity.peer_id());
+        alice.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+
+        let message_id = Uuid::new_v4();
+        let message = Message::chat_message(message_id, "alice".to_string(), "all".to_string(), "hello bob".to_string(), alice.manager.channel.clone(), &alice.identity);
+        let recipients = alice.manager.broadcast_message(&message).await.unwrap();
+        assert_eq!(recipients, vec![bob.identity.peer_id()]);
+
+        let received = bob.event(|event| matches!(event.message, Message::ChatMessage { .. })).await;
+        assert!(received.verified);
+        let Message::ChatMessage { content, sender_id, message_id: received_id, .. } = received.message else {
+            unreachable!();
+        };
+        assert_eq!((content.as_str(), sender_id, received_id), ("hello bob", alice.identity.peer_id(), message_id));
+
+        let ack = alice.event(|event| matches!(event.message, Message::MessageAck { .. })).await;
+        assert!(matches!(ack.message, Message::MessageAck { message_id: acked, .. } if acked == message_id));
+    }
+
+    #[tokio::test]
+    async fn wrong_channel_key_fails_the_noise_handshake() {
+        let alice = TestPeer::start("alice", channel(Some("right"))).await;
+        let bob = TestPeer::start("bob", channel(Some("wrong"))).await;
+
+        let error = alice.manager.connect_to_peer(&bob.as_peer()).await.unwrap_err();
+        assert!(format!("{:#}", error).contains("Noise handshake"), "{:#}", error);
+        assert!(!alice.manager.is_connected(&bob.identity.peer_id()).await);
+        assert!(!bob.manager.is_connected(&alice.identity.peer_id()).await);
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_does_not_hold_up_the_connection_table() {
+        let config = Config { network_timeout: 2, heartbeat_interval: 1, ..Config::new() };
+        let mut alice = TestPeer::start_with(config, "alice", channel(None)).await;
+        let bob = TestPeer::start("bob", channel(None)).await;
+
+        let (writer, socket, _unread) = stalled_writer(&alice.manager).await;
+        let stalled = Peer::new("stalled".to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+        let connection = PeerConnection {
+            peer: stalled.clone(),
+            writer,
+            socket,
+            session: Session { version: RECEIPTS_MIN_VERSION, capabilities: Capabilities::supported() },
+            heartbeat: tokio::spawn(async {}),
+            outbound: true,
+        };
+        alice.manager.connections.write().await.insert(stalled.id, connection);
+
+        let message = Message::chat_message(Uuid::new_v4(), "alice".to_string(), "all".to_string(), "anyone there?".to_string(), alice.manager.channel.clone(), &alice.identity);
+        let manager = alice.manager.clone();
+        let broadcast = tokio::spawn(async move { manager.broadcast_message(&message).await });
+        sleep(Duration::from_millis(100)).await;
+
+        // Registering a new peer needs both locks the broadcast used to hold
+        timeout(Duration::from_secs(1), bob.manager.connect_to_peer(&alice.as_peer())).await
+            .expect("dialing alice stalled behind the broadcast")
+            .unwrap();
+        alice.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+        assert!(!broadcast.is_finished());
+
+        let recipients = broadcast.await.unwrap().unwrap();
+        assert!(recipients.contains(&stalled.id));
+        assert!(alice.manager.outbox.lock().await.waiting_peers().contains(&stalled.id));
+        // ...and the stalled session is dropped rather than written to again
+        assert!(!alice.manager.is_connected(&stalled.id).await);
+        assert!(alice.manager.is_connected(&bob.identity.peer_id()).await);
+    }
+
+    #[tokio::test]
+    async fn replay_to_a_stalled_peer_does_not_hold_up_the_connection_table() {
+        let config = Config { network_timeout: 2, ..Config::new() };
+        let mut alice = TestPeer::start_with(config, "alice", channel(None)).await;
+        let bob = TestPeer::start("bob", channel(None)).await;
+
+        // A peer coming back to a held message, that stops reading
+        let (writer, _, _unread) = stalled_writer(&alice.manager).await;
+        let stalled = Peer::new("stalled".to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+        let held = Message::chat_message(Uuid::new_v4(), "alice".to_string(), "all".to_string(), "while you were out".to_string(), alice.manager.channel.clone(), &alice.identity);
+        {
+            let mut outbox = alice.manager.outbox.lock().await;
+            outbox.hold_for(stalled.id);
+            outbox.push(stalled.id, held);
+        }
+        let join = Message::user_join("stalled".to_string(), stalled.id, String::new(), alice.manager.channel.clone());
+        let session = Session { version: RECEIPTS_MIN_VERSION, capabilities: Capabilities::supported() };
+        let manager = alice.manager.clone();
+        let peer = stalled.clone();
+        let registering = tokio::spawn(async move { manager.register_connection(&peer, writer, session, join, None, true).await });
+        sleep(Duration::from_millis(100)).await;
+
+        timeout(Duration::from_secs(1), bob.manager.connect_to_peer(&alice.as_peer())).await
+            .expect("dialing alice stalled behind the replay")
+            .unwrap();
+        let bob_id = bob.identity.peer_id();
+        alice.event(|event| matches!(event.message, Message::UserJoin { .. }) && event.peer.id == bob_id).await;
+        assert!(!registering.is_finished());
+
+        // Once the replay times out the connection is dropped and the
+        // message held again
+        assert!(registering.await.unwrap());
+        assert!(!alice.manager.is_connected(&stalled.id).await);
+        assert_eq!(alice.manager.outbox.lock().await.take(&stalled.id).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn evicted_connection_is_reported_as_lost() {
+        let mut alice = TestPeer::start("alice", channel(None)).await;
+        let mut bob = TestPeer::start("bob", channel(None)).await;
+        let bob_at = bob.as_peer();
+        alice.manager.connect_to_peer(&bob_at).await.unwrap();
+        alice.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+        bob.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+
+        let (peer, writer) = {
+            let connections = alice.manager.connections.read().await;
+            let connection = connections.get(&bob_at.id).unwrap();
+            (connection.peer.clone(), connection.writer.clone())
+        };
+        alice.manager.evict(&peer, &writer, &anyhow!("write timed out"), Vec::new()).await;
+        assert!(!alice.manager.is_connected(&bob_at.id).await);
+        assert!(alice.manager.outbox.lock().await.waiting_peers().contains(&bob_at.id));
+
+        // Both readers notice the socket is gone
+        let lost = alice.event(|event| matches!(event.message, Message::UserLeave { .. })).await;
+        assert_eq!(lost.peer.id, bob_at.id);
+        bob.event(|event| matches!(event.message, Message::UserLeave { .. })).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn simultaneous_dials_keep_the_lower_ids_connection() {
+        let mut alice = TestPeer::start("alice", channel(None)).await;
+        let mut bob = TestPeer::start("bob", channel(None)).await;
+
+        let (alice_at, bob_at) = (alice.as_peer(), bob.as_peer());
+        let (alice_dial, bob_dial) = tokio::join!(
+            alice.manager.connect_to_peer(&bob_at),
+            bob.manager.connect_to_peer(&alice_at),
+        );
+        alice_dial.unwrap();
+        bob_dial.unwrap();
+        // Let the losing connection's close reach both ends
+        sleep(Duration::from_millis(500)).await;
+
+        let lower = alice.identity.peer_id().min(bob.identity.peer_id());
+        for side in [&alice, &bob] {
+            let connections = side.manager.connections.read().await;
+            assert_eq!(connections.len(), 1, "{} has {} connections", side.manager.username, connections.len());
+            let connection = connections.values().next().unwrap();
+            // The lower id dialed the one both kept
+            assert_eq!(connection.outbound, side.identity.peer_id() == lower, "{} kept the wrong connection", side.manager.username);
+        }
+
+        for side in [&mut alice, &mut bob] {
+            let mut joins = 0;
+            while let Ok(event) = side.events.try_recv() {
+                match event.message {
+                    Message::UserJoin { .. } => joins += 1,
+                    Message::UserLeave { .. } => panic!("{} saw {} leave", side.manager.username, event.peer.username),
+                    _ => {}
+                }
+            }
+            // Twice if the losing connection was gone before the winner
+            // registered; the UI takes that as the same peer
+            assert!(joins >= 1);
+        }
+
+        // And the connection they kept works both ways
+        let message = Message::chat_message(Uuid::new_v4(), "bob".to_string(), "all".to_string(), "still here".to_string(), bob.manager.channel.clone(), &bob.identity);
+        assert_eq!(bob.manager.broadcast_message(&message).await.unwrap(), vec![alice.identity.peer_id()]);
+        alice.event(|event| matches!(event.message, Message::ChatMessage { .. })).await;
+        bob.event(|event| matches!(event.message, Message::MessageAck { .. })).await;
+    }
+
+    #[tokio::test]
+    async fn dial_to_a_silent_address_times_out() {
+        let config = Config { network_timeout: 1, ..Config::new() };
+        let alice = TestPeer::start_with(config, "alice", channel(None)).await;
+
+        // A listener that never accepts drops SYNs once its queue is full,
+        // like a firewall that eats them
+        let silent = socket2::Socket::new(socket2::Domain::IPV4, socket2::Type::STREAM, None).unwrap();
+        silent.bind(&SocketAddr::from((Ipv4Addr::LOCALHOST, 0)).into()).unwrap();
+        silent.listen(0).unwrap();
+        let addr = silent.local_addr().unwrap().as_socket().unwrap();
+        let mut queued = Vec::new();
+        while let Ok(stream) = std::net::TcpStream::connect_timeout(&addr, Duration::from_millis(200)) {
+            queued.push(stream);
+        }
+
+        let target = Peer::new("silent".to_string(), addr.ip(), addr.port());
+        let dialed = timeout(Duration::from_secs(3), alice.manager.connect_to_peer(&target)).await
+            .expect("dial waited out the kernel's connect timeout");
+        assert!(dialed.is_err());
+    }
+
+    #[tokio::test]
+    async fn dead_address_is_given_up_on() {
+        let config = Config { network_timeout: 2, reconnect_delay: 1, ..Config::new() };
+        let mut alice = TestPeer::start_with(config, "alice", channel(None)).await;
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let target = closed.to_string();
+
+        let dialed = timeout(Duration::from_secs(5), alice.manager.clone().connect_to_address(target.clone(), false)).await
+            .expect("kept dialing a dead address");
+        assert!(dialed.is_err());
+        // Nothing about it goes out as a peer event
+        assert!(alice.events.try_recv().is_err());
+        // ...and the same address can be tried again
+        assert!(!alice.manager.dialing.lock().await.contains(&target));
+    }
+
+    #[tokio::test]
+    async fn seed_address_is_dialed_past_network_timeout() {
+        let config = Config { network_timeout: 1, reconnect_delay: 1, reconnect_max_delay: 1, ..Config::new() };
+        let mut alice = TestPeer::start_with(config, "alice", channel(None)).await;
+        let closed = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let target = closed.to_string();
+
+        let dialing = tokio::spawn(alice.manager.clone().connect_to_address(target.clone(), true));
+        sleep(Duration::from_secs(3)).await;
+        assert!(!dialing.is_finished(), "gave up on a seed that may just not be up yet");
+        assert!(alice.manager.dialing.lock().await.contains(&target));
+        assert!(alice.events.try_recv().is_err());
+
+        alice.manager.shutdown(Duration::from_secs(1)).await;
+        timeout(Duration::from_secs(3), dialing).await
+            .expect("kept dialing after shutdown")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn stalled_peer_does_not_hold_up_the_other_goodbyes() {
+        let alice = TestPeer::start("alice", channel(None)).await;
+        let mut bob = TestPeer::start("bob", channel(None)).await;
+        let (writer, socket, _unread) = stalled_writer(&alice.manager).await;
+        let stalled = Peer::new("stalled".to_string(), IpAddr::V4(Ipv4Addr::LOCALHOST), 1);
+        let connection = PeerConnection {
+            peer: stalled.clone(),
+            writer,
+            socket,
+            session: Session { version: RECEIPTS_MIN_VERSION, capabilities: Capabilities::supported() },
+            heartbeat: tokio::spawn(async {}),
+            outbound: true,
+        };
+        alice.manager.connections.write().await.insert(stalled.id, connection);
+        bob.manager.connect_to_peer(&alice.as_peer()).await.unwrap();
+        bob.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+
+        timeout(Duration::from_secs(2), alice.manager.shutdown(Duration::from_secs(1))).await
+            .expect("shutdown waited on the stalled peer past its limit");
+        let leave = bob.event(|event| matches!(event.message, Message::UserLeave { .. })).await;
+        assert_eq!(leave.peer.id, alice.identity.peer_id());
+        assert!(leave.status.is_none());
+    }
+
+    #[tokio::test]
+    async fn second_hand_messages_are_verified_only_from_known_authors() {
+        let mut alice = TestPeer::start("alice", channel(None)).await;
+        let mut bob = TestPeer::start("bob", channel(None)).await;
+        let mut carol = TestPeer::start("carol", channel(None)).await;
+        for other in [&mut bob, &mut carol] {
+            other.manager.connect_to_peer(&alice.as_peer()).await.unwrap();
+            let id = other.identity.peer_id();
+            alice.event(|event| matches!(event.message, Message::UserJoin { .. }) && event.peer.id == id).await;
+        }
+
+        // A key nobody has met, signing as "dave", handed over by bob
+        let dave = Identity::ephemeral();
+        let made_up = Message::chat_message(Uuid::new_v4(), "dave".to_string(), "all".to_string(), "trust me".to_string(), alice.manager.channel.clone(), &dave);
+        bob.manager.broadcast_message(&made_up).await.unwrap();
+        let received = alice.event(|event| matches!(event.message, Message::ChatMessage { .. })).await;
+        assert!(!received.verified);
+
+        // carol has a session with alice, so her signature counts
+        let from_carol = Message::chat_message(Uuid::new_v4(), "carol".to_string(), "all".to_string(), "hi".to_string(), alice.manager.channel.clone(), &carol.identity);
+        bob.manager.broadcast_message(&from_carol).await.unwrap();
+        let received = alice.event(|event| matches!(event.message, Message::ChatMessage { .. })).await;
+        assert!(received.verified);
+        assert_eq!(received.peer.id, bob.identity.peer_id());
+    }
+
+    #[tokio::test]
+    async fn history_nobody_asked_for_is_ignored() {
+        let mut alice = TestPeer::start("alice", channel(None)).await;
+        let bob = TestPeer::start("bob", channel(None)).await;
+        bob.manager.connect_to_peer(&alice.as_peer()).await.unwrap();
+        alice.event(|event| matches!(event.message, Message::UserJoin { .. })).await;
+        // Let bob answer the request alice made after the handshake
+        sleep(Duration::from_millis(300)).await;
+        assert!(!alice.manager.history_requested.lock().await.contains(&bob.identity.peer_id()));
+
+        let said = Message::chat_message(Uuid::new_v4(), "bob".to_string(), "all".to_string(), "as I was saying".to_string(), alice.manager.channel.clone(), &bob.identity);
+        bob.manager.broadcast_message(&Message::history_response(vec![said])).await.unwrap();
+        let ping = Message::chat_message(Uuid::new_v4(), "bob".to_string(), "all".to_string(), "ping".to_string(), alice.manager.channel.clone(), &bob.identity);
+        bob.manager.broadcast_message(&ping).await.unwrap();
+
+        // The live message arrives; the unrequested history before it didn't
+        let received = alice.event(|event| matches!(event.message, Message::ChatMessage { .. })).await;
+        assert!(!received.backfilled);
+        assert!(matches!(received.message, Message::ChatMessage { ref content, .. } if content == "ping"));
+    }
+}
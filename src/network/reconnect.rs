@@ -0,0 +1,29 @@
+use rand::Rng;
+use tokio::time::Duration;
+
+/// Delays between attempts to reach a peer we lost: `initial`, doubling each
+/// time up to `max`. Each delay is picked at random from the upper half of
+/// that range, so two peers that lost each other at the same moment don't
+/// keep dialing in lockstep.
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    attempts: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration) -> Self {
+        Self {
+            initial,
+            max,
+            attempts: 0,
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self.initial.saturating_mul(1 << self.attempts.min(16)).min(self.max);
+        self.attempts += 1;
+        let half = ceiling / 2;
+        half + half.mul_f64(rand::thread_rng().gen::<f64>())
+    }
+}
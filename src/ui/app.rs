@@ -1,47 +1,137 @@
-use crate::message::{ChatEvent, Message, Peer};
-use chrono::{DateTime, Utc};
-use std::collections::HashMap;
-use tokio::sync::mpsc;
+use crate::message::{ChatEvent, Message, Peer, PeerStatus};
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
+use tokio::sync::{mpsc, oneshot};
 use uuid::Uuid;
 
+// Messages kept per list (group chat, private messages)
+const MESSAGE_LIMIT: usize = 100;
+
 #[derive(Debug, Clone)]
 pub struct ChatMessage {
     pub sender: String,
     pub content: String,
     pub timestamp: DateTime<Utc>,
     pub is_own_message: bool,
+    pub verified: bool, // signature checked against the sender's identity key
+    pub recipient: Option<String>, // set on private messages we sent
+    pub backfilled: bool, // merged in from a peer's history rather than received live
+    pub relayed_by: Option<String>, // peer that forwarded a group message written by someone else
+    pub message_id: Uuid,
+    // Our messages only: receipt state per recipient, None until the network
+    // layer has reported who it sent the message to
+    pub deliveries: Option<HashMap<Uuid, Delivery>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delivery {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+/// Where one of our messages stands, summed up over all its recipients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Partial { delivered: usize, total: usize },
+    Failed,
+}
+
+impl ChatMessage {
+    pub fn delivery_status(&self) -> Option<DeliveryStatus> {
+        if !self.is_own_message {
+            return None;
+        }
+        let Some(deliveries) = &self.deliveries else {
+            return Some(DeliveryStatus::Pending);
+        };
+        if deliveries.values().any(|delivery| *delivery == Delivery::Pending) {
+            return Some(DeliveryStatus::Pending);
+        }
+        let delivered = deliveries.values().filter(|delivery| **delivery == Delivery::Delivered).count();
+        Some(match delivered {
+            0 => DeliveryStatus::Failed,
+            n if n == deliveries.len() => DeliveryStatus::Delivered,
+            n => DeliveryStatus::Partial { delivered: n, total: deliveries.len() },
+        })
+    }
+}
+
+/// What the UI asks the network layer to send.
+#[derive(Debug, Clone)]
+pub enum Outgoing {
+    Broadcast { message_id: Uuid, content: String },
+    Direct { message_id: Uuid, peer_id: Uuid, content: String },
+}
+
+/// What the UI asks the network layer to do about a peer.
+#[derive(Debug)]
+pub enum ConnectionRequest {
+    Connect(Peer), // discovery found it; dial if we aren't connected yet
+    Forget(Uuid), // it left for good; stop trying to reach it
+    Dial(String, oneshot::Sender<Result<(), String>>), // "host:port" from `/connect`; dial whoever is there, then say how it went
+}
+
+/// What the network layer did with an `Outgoing` message.
+#[derive(Debug, Clone)]
+pub struct SendReport {
+    pub message_id: Uuid,
+    pub recipients: Vec<Uuid>, // peers expected to send a receipt
+    pub error: Option<String>,
 }
 
 pub struct App {
     pub username: String,
     pub peers: HashMap<Uuid, Peer>,
     pub messages: Vec<ChatMessage>,
+    pub private_messages: Vec<ChatMessage>, // 1:1 conversations, kept apart from the group chat
     pub input: String,
     pub should_quit: bool,
     pub status: String,
+    pub alert: Option<String>, // security warnings stay on screen until replaced
+    pub key_changed_peers: HashSet<Uuid>,
+    connected_peers: HashSet<Uuid>, // peers with a live TCP session
+    peer_status: HashMap<Uuid, PeerStatus>, // peers we lost and are trying to get back
+    early_receipts: HashMap<Uuid, HashSet<Uuid>>, // receipts that got here before the message's send report
+    dials: Vec<(String, oneshot::Receiver<Result<(), String>>)>, // `/connect`s still in progress
     pub channel: Option<String>,
+    peer_timeout: Duration, // drop peers we haven't heard from in this long, and give up on receipts
     event_receiver: mpsc::UnboundedReceiver<ChatEvent>,
-    message_sender: mpsc::UnboundedSender<String>,
-    connection_sender: Option<mpsc::UnboundedSender<Peer>>,
+    report_receiver: mpsc::UnboundedReceiver<SendReport>,
+    message_sender: mpsc::UnboundedSender<Outgoing>,
+    connection_sender: Option<mpsc::UnboundedSender<ConnectionRequest>>,
 }
 
 impl App {
     pub fn new(
         username: String,
         event_receiver: mpsc::UnboundedReceiver<ChatEvent>,
-        message_sender: mpsc::UnboundedSender<String>,
-        connection_sender: Option<mpsc::UnboundedSender<Peer>>,
+        report_receiver: mpsc::UnboundedReceiver<SendReport>,
+        message_sender: mpsc::UnboundedSender<Outgoing>,
+        connection_sender: Option<mpsc::UnboundedSender<ConnectionRequest>>,
         channel: Option<String>,
+        peer_timeout: Duration,
     ) -> Self {
         Self {
             username,
             peers: HashMap::new(),
             messages: Vec::new(),
+            private_messages: Vec::new(),
             input: String::new(),
             should_quit: false,
             status: "Starting...".to_string(),
+            alert: None,
+            key_changed_peers: HashSet::new(),
+            connected_peers: HashSet::new(),
+            peer_status: HashMap::new(),
+            early_receipts: HashMap::new(),
+            dials: Vec::new(),
             channel,
+            peer_timeout,
             event_receiver,
+            report_receiver,
             message_sender,
             connection_sender,
         }
@@ -51,35 +141,172 @@ impl App {
         self.status = status;
     }
 
-    pub fn add_message(&mut self, sender: String, content: String, is_own_message: bool) {
+    pub fn add_message(&mut self, message_id: Uuid, sender: String, content: String, is_own_message: bool, verified: bool, relayed_by: Option<String>) {
         let message = ChatMessage {
             sender,
             content,
             timestamp: Utc::now(),
             is_own_message,
+            verified,
+            recipient: None,
+            backfilled: false,
+            message_id,
+            relayed_by,
+            deliveries: None,
         };
-        
-        self.messages.push(message);
+        Self::push_message(&mut self.messages, message);
+    }
+
+    pub fn add_private_message(&mut self, message_id: Uuid, sender: String, recipient: Option<String>, content: String, is_own_message: bool, verified: bool) {
+        let message = ChatMessage {
+            sender,
+            content,
+            timestamp: Utc::now(),
+            is_own_message,
+            verified,
+            recipient,
+            backfilled: false,
+            message_id,
+            relayed_by: None,
+            deliveries: None,
+        };
+        Self::push_message(&mut self.private_messages, message);
+    }
+
+    /// Merges a message from another peer's history into the group chat at
+    /// its place in time, unless we already have it.
+    pub fn add_backfilled_message(&mut self, message_id: Uuid, sender: String, content: String, timestamp: DateTime<Utc>, verified: bool) {
+        if self.messages.iter().any(|message| message.message_id == message_id) {
+            return;
+        }
+        let message = ChatMessage {
+            sender,
+            content,
+            timestamp,
+            is_own_message: false,
+            verified,
+            recipient: None,
+            backfilled: true,
+            message_id,
+            relayed_by: None,
+            deliveries: None,
+        };
+        let at = self.messages.partition_point(|existing| existing.timestamp <= timestamp);
+        self.messages.insert(at, message);
+        if self.messages.len() > MESSAGE_LIMIT {
+            self.messages.remove(0);
+        }
+    }
+
+    fn push_message(messages: &mut Vec<ChatMessage>, message: ChatMessage) {
+        messages.push(message);
         
         // Keep only last 100 messages to prevent memory issues
-        if self.messages.len() > 100 {
-            self.messages.remove(0);
+        if messages.len() > MESSAGE_LIMIT {
+            messages.remove(0);
         }
     }
 
     pub fn send_message(&mut self) {
-        if !self.input.trim().is_empty() {
-            let content = self.input.trim().to_string();
-            
-            // Add to our own message history
-            self.add_message(self.username.clone(), content.clone(), true);
-            
-            // Send to network
-            if let Err(e) = self.message_sender.send(content) {
-                self.update_status(format!("Failed to send message: {}", e));
+        let input = self.input.trim().to_string();
+        self.input.clear();
+        if input.is_empty() {
+            return;
+        }
+        
+        if let Some(args) = input.strip_prefix("/msg").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            self.send_private_message(args.trim());
+            return;
+        }
+        if let Some(args) = input.strip_prefix("/connect").filter(|rest| rest.is_empty() || rest.starts_with(' ')) {
+            self.connect_to(args.trim());
+            return;
+        }
+        
+        // Add to our own message history
+        let message_id = Uuid::new_v4();
+        self.add_message(message_id, self.username.clone(), input.clone(), true, true, None);
+        
+        // Send to network
+        if let Err(e) = self.message_sender.send(Outgoing::Broadcast { message_id, content: input }) {
+            self.update_status(format!("Failed to send message: {}", e));
+        }
+    }
+
+    // `/msg <user> <text>`: only goes to that peer's TCP session, so it has
+    // to be connected right now.
+    fn send_private_message(&mut self, args: &str) {
+        let Some((name, content)) = args
+            .split_once(' ')
+            .map(|(name, content)| (name, content.trim()))
+            .filter(|(_, content)| !content.is_empty())
+        else {
+            self.update_status("Usage: /msg <user> <text>".to_string());
+            return;
+        };
+        
+        let matches: Vec<&Peer> = self.peers.values().filter(|peer| peer.username == name).collect();
+        let peer_id = match matches.as_slice() {
+            [] => {
+                self.update_status(format!("No peer called {}; private message not sent", name));
+                return;
             }
-            
-            self.input.clear();
+            [peer] => peer.id,
+            _ => {
+                self.update_status(format!("Several peers are called {}; private message not sent", name));
+                return;
+            }
+        };
+        if !self.connected_peers.contains(&peer_id) {
+            self.update_status(format!("{} is offline; private message not sent", name));
+            return;
+        }
+        
+        let message_id = Uuid::new_v4();
+        self.add_private_message(message_id, self.username.clone(), Some(name.to_string()), content.to_string(), true, true);
+        let outgoing = Outgoing::Direct { message_id, peer_id, content: content.to_string() };
+        if let Err(e) = self.message_sender.send(outgoing) {
+            self.update_status(format!("Failed to send message: {}", e));
+        }
+    }
+
+    // `/connect <host:port>`: for peers discovery can't find, e.g. over a VPN.
+    fn connect_to(&mut self, target: &str) {
+        let valid = target
+            .rsplit_once(':')
+            .is_some_and(|(host, port)| !host.is_empty() && !target.contains(' ') && port.parse::<u16>().is_ok());
+        if !valid {
+            self.update_status("Usage: /connect <host:port>".to_string());
+            return;
+        }
+        
+        let Some(ref connection_sender) = self.connection_sender else {
+            return;
+        };
+        let (reply_sender, reply) = oneshot::channel();
+        match connection_sender.send(ConnectionRequest::Dial(target.to_string(), reply_sender)) {
+            Ok(()) => {
+                self.dials.push((target.to_string(), reply));
+                self.update_status(format!("Connecting to {}...", target));
+            }
+            Err(e) => self.update_status(format!("Failed to connect to {}: {}", target, e)),
+        }
+    }
+
+    // Reports `/connect`s that failed. One that worked shows up as the
+    // peer's user_join.
+    fn check_dials(&mut self) {
+        let mut failed = Vec::new();
+        self.dials.retain_mut(|(target, reply)| match reply.try_recv() {
+            Err(oneshot::error::TryRecvError::Empty) => true,
+            Ok(Ok(())) | Err(oneshot::error::TryRecvError::Closed) => false,
+            Ok(Err(e)) => {
+                failed.push(format!("Couldn't reach {}: {}", target, e));
+                false
+            }
+        });
+        for status in failed {
+            self.update_status(status);
         }
     }
 
@@ -96,44 +323,188 @@ impl App {
     }
 
     pub async fn handle_events(&mut self) {
+        // Reports first, so receipts that arrived right behind them find
+        // their recipient list in place
+        while let Ok(report) = self.report_receiver.try_recv() {
+            self.handle_send_report(report);
+        }
         // Non-blocking check for new events
         while let Ok(event) = self.event_receiver.try_recv() {
             self.handle_chat_event(event);
         }
+        self.check_dials();
+        self.expire_peers();
+        self.expire_deliveries();
+    }
+
+    fn handle_send_report(&mut self, report: SendReport) {
+        if let Some(error) = &report.error {
+            self.update_status(format!("Failed to send message: {}", error));
+        }
+        let early = self.early_receipts.remove(&report.message_id).unwrap_or_default();
+        if let Some(message) = self.own_message_mut(report.message_id) {
+            let deliveries = report.recipients
+                .into_iter()
+                .map(|peer_id| {
+                    let delivery = if early.contains(&peer_id) { Delivery::Delivered } else { Delivery::Pending };
+                    (peer_id, delivery)
+                })
+                .collect();
+            message.deliveries = Some(deliveries);
+        }
+    }
+
+    fn own_message_mut(&mut self, message_id: Uuid) -> Option<&mut ChatMessage> {
+        self.messages
+            .iter_mut()
+            .chain(self.private_messages.iter_mut())
+            .find(|message| message.is_own_message && message.message_id == message_id)
+    }
+
+    // Settles a pending receipt. A receipt can still turn a timed-out
+    // delivery into a delivered one: messages held for a peer that dropped
+    // off are only confirmed once it reconnects. A fast peer's receipt can
+    // also beat the send report; it is kept until the report shows up.
+    fn settle_delivery(&mut self, message_id: Uuid, peer_id: Uuid, outcome: Delivery) {
+        let Some(message) = self.own_message_mut(message_id) else {
+            return;
+        };
+        let Some(deliveries) = message.deliveries.as_mut() else {
+            if outcome == Delivery::Delivered {
+                self.early_receipts.entry(message_id).or_default().insert(peer_id);
+            }
+            return;
+        };
+        if let Some(delivery) = deliveries.get_mut(&peer_id) {
+            if let (Delivery::Pending, _) | (Delivery::Failed, Delivery::Delivered) = (*delivery, outcome) {
+                *delivery = outcome;
+            }
+        }
+    }
+
+    // A peer that leaves won't confirm anything it hasn't yet.
+    fn fail_deliveries_to(&mut self, peer_id: Uuid) {
+        let deliveries = self.messages
+            .iter_mut()
+            .chain(self.private_messages.iter_mut())
+            .filter_map(|message| message.deliveries.as_mut()?.get_mut(&peer_id));
+        for delivery in deliveries.filter(|delivery| **delivery == Delivery::Pending) {
+            *delivery = Delivery::Failed;
+        }
+    }
+
+    fn expire_deliveries(&mut self) {
+        let cutoff = Utc::now() - self.peer_timeout;
+        let stale = self.messages
+            .iter_mut()
+            .chain(self.private_messages.iter_mut())
+            .filter(|message| message.is_own_message && message.timestamp < cutoff);
+        for message in stale {
+            self.early_receipts.remove(&message.message_id);
+            let deliveries = message.deliveries.get_or_insert_with(HashMap::new);
+            for delivery in deliveries.values_mut().filter(|delivery| **delivery == Delivery::Pending) {
+                *delivery = Delivery::Failed;
+            }
+        }
+    }
+
+    fn expire_peers(&mut self) {
+        let cutoff = Utc::now() - self.peer_timeout;
+        let expired: Vec<Uuid> = self.peers
+            .values()
+            .filter(|peer| peer.last_seen < cutoff)
+            .map(|peer| peer.id)
+            .collect();
+        for id in expired {
+            self.connected_peers.remove(&id);
+            self.peer_status.remove(&id);
+            self.fail_deliveries_to(id);
+            if let Some(peer) = self.peers.remove(&id) {
+                self.update_status(format!("Lost contact with {}", peer.username));
+            }
+        }
     }
 
     fn handle_chat_event(&mut self, event: ChatEvent) {
+        let private = event.message.recipient_id().is_some();
         match event.message {
             Message::Discovery { username, .. } => {
-                self.peers.insert(event.peer.id, event.peer);
+                self.upsert_peer(event.peer);
                 self.update_status(format!("Discovered peer: {}", username));
             }
             Message::DiscoveryResponse { username, .. } => {
-                self.peers.insert(event.peer.id, event.peer.clone());
+                self.upsert_peer(event.peer.clone());
                 self.update_status(format!("Found peer: {}", username));
                 
                 // Trigger TCP connection to this peer
                 if let Some(ref connection_sender) = self.connection_sender {
-                    if let Err(e) = connection_sender.send(event.peer.clone()) {
+                    if let Err(e) = connection_sender.send(ConnectionRequest::Connect(event.peer.clone())) {
                         self.update_status(format!("Failed to trigger connection to {}: {}", username, e));
                     } else {
                         self.update_status(format!("Connecting to {}...", username));
                     }
                 }
             }
-            Message::ChatMessage { sender, content, .. } => {
-                self.add_message(sender, content, false);
+            Message::ChatMessage { sender, content, message_id, timestamp, .. } if event.backfilled => {
+                let sender = Self::sender_name(sender, &event.peer, event.verified);
+                self.add_backfilled_message(message_id, sender, content, timestamp, event.verified);
+            }
+            Message::ChatMessage { sender, content, message_id, .. } if private => {
+                let sender = Self::sender_name(sender, &event.peer, event.verified);
+                self.add_private_message(message_id, sender, None, content, false, event.verified);
+            }
+            Message::ChatMessage { sender, sender_id, content, message_id, .. } => {
+                // Signed by someone other than the peer that handed it to us
+                let relayed_by = (event.verified && sender_id != event.peer.id).then(|| event.peer.username.clone());
+                let sender = Self::sender_name(sender, &event.peer, event.verified);
+                self.add_message(message_id, sender, content, false, event.verified, relayed_by);
+            }
+            Message::HistoryRequest { .. } | Message::HistoryResponse { .. } | Message::PeerList { .. } => {
+                // Answered and unpacked by the peer manager
+            }
+            Message::MessageAck { message_id, .. } => {
+                self.settle_delivery(message_id, event.peer.id, Delivery::Delivered);
             }
             Message::UserJoin { username, .. } => {
+                if let Some(warning) = event.warning {
+                    self.key_changed_peers.insert(event.peer.id);
+                    self.alert = Some(warning);
+                }
+                self.connected_peers.insert(event.peer.id);
+                self.peer_status.remove(&event.peer.id);
                 self.peers.insert(event.peer.id, event.peer);
                 self.update_status(format!("{} joined via TCP", username));
             }
             Message::UserLeave { username, .. } => {
-                self.peers.remove(&event.peer.id);
-                self.update_status(format!("{} left the chat", username));
+                self.connected_peers.remove(&event.peer.id);
+                self.fail_deliveries_to(event.peer.id);
+                match event.status {
+                    // We lost the connection; keep the peer listed while the
+                    // network layer tries to get it back
+                    Some(status) => {
+                        self.update_status(match status {
+                            PeerStatus::Reconnecting { attempt: 0 } => format!("Lost connection to {}", username),
+                            PeerStatus::Reconnecting { attempt } => format!("Reconnecting to {} (attempt {})...", username, attempt),
+                            PeerStatus::Offline => format!("{} is offline", username),
+                        });
+                        self.peer_status.insert(event.peer.id, status);
+                        self.peers.entry(event.peer.id).or_insert(event.peer);
+                    }
+                    None => {
+                        self.peer_status.remove(&event.peer.id);
+                        self.peers.remove(&event.peer.id);
+                        if let Some(ref connection_sender) = self.connection_sender {
+                            let _ = connection_sender.send(ConnectionRequest::Forget(event.peer.id));
+                        }
+                        self.update_status(format!("{} left the chat", username));
+                    }
+                }
             }
-            Message::Heartbeat { .. } => {
-                // Update peer's last seen time
+            Message::JoinRejected { reason, .. } => {
+                self.update_status(format!("{} rejected our connection: {}", event.peer.username, reason));
+            }
+            Message::Heartbeat { .. } | Message::HeartbeatAck { .. } => {
+                // Update peer's last seen time (and round trip, for acks)
                 if let Some(peer) = self.peers.get_mut(&event.peer.id) {
                     *peer = event.peer;
                 }
@@ -141,6 +512,21 @@ impl App {
         }
     }
 
+    // An unsigned message only proves which session it came in on, so it is
+    // shown under that peer's name rather than whatever `sender` it claims.
+    fn sender_name(sender: String, peer: &Peer, verified: bool) -> String {
+        if verified { sender } else { peer.username.clone() }
+    }
+
+    // Discovery refreshes last_seen but knows nothing about the TCP session,
+    // so keep the round trip we measured over it.
+    fn upsert_peer(&mut self, mut peer: Peer) {
+        if let Some(existing) = self.peers.get(&peer.id) {
+            peer.rtt_ms = peer.rtt_ms.or(existing.rtt_ms);
+        }
+        self.peers.insert(peer.id, peer);
+    }
+
     pub fn get_peer_count(&self) -> usize {
         self.peers.len()
     }
@@ -148,7 +534,137 @@ impl App {
     pub fn get_peer_list(&self) -> Vec<String> {
         self.peers
             .values()
-            .map(|peer| format!("{} ({})", peer.username, peer.ip))
+            .map(|peer| {
+                let mut line = format!("{} ({})", peer.username, peer.ip);
+                if let Some(rtt) = peer.rtt_ms {
+                    line.push_str(&format!(" {}ms", rtt));
+                }
+                match self.peer_status.get(&peer.id) {
+                    Some(PeerStatus::Reconnecting { .. }) => line.push_str(" ⟳ reconnecting"),
+                    Some(PeerStatus::Offline) => line.push_str(" ✗ offline"),
+                    None => {}
+                }
+                if self.key_changed_peers.contains(&peer.id) {
+                    line.push_str(" ⚠ KEY CHANGED");
+                }
+                line
+            })
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::message::MessageHandler;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::Arc;
+
+    struct Harness {
+        app: App,
+        events: mpsc::UnboundedSender<ChatEvent>,
+        reports: mpsc::UnboundedSender<SendReport>,
+        outgoing: mpsc::UnboundedReceiver<Outgoing>,
+    }
+
+    fn harness() -> Harness {
+        let (events, event_receiver) = mpsc::unbounded_channel();
+        let (reports, report_receiver) = mpsc::unbounded_channel();
+        let (message_sender, outgoing) = mpsc::unbounded_channel();
+        let app = App::new("me".to_string(), event_receiver, report_receiver, message_sender, None, None, Duration::seconds(10));
+        Harness { app, events, reports, outgoing }
+    }
+
+    fn peer(username: &str) -> Peer {
+        Peer::new(username.to_string(), IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), 8000)
+    }
+
+    #[tokio::test]
+    async fn unsigned_messages_show_the_sessions_username() {
+        let Harness { mut app, events, .. } = harness();
+        let bob = peer("bob");
+        let unsigned = Message::ChatMessage {
+            sender: "alice".to_string(),
+            sender_id: Uuid::nil(),
+            public_key: String::new(),
+            recipient: "all".to_string(),
+            content: "it's me, alice".to_string(),
+            timestamp: Utc::now(),
+            message_id: Uuid::new_v4(),
+            channel: None,
+            signature: String::new(),
+            hop_limit: 0,
+        };
+        events.send(ChatEvent::new(bob, unsigned)).unwrap();
+        app.handle_events().await;
+
+        let shown = app.messages.last().unwrap();
+        assert_eq!(shown.sender, "bob");
+        assert!(!shown.verified);
+    }
+
+    #[tokio::test]
+    async fn receipt_that_beats_the_send_report_still_counts() {
+        let Harness { mut app, events, reports, mut outgoing } = harness();
+        let bob = peer("bob");
+        app.input = "hi".to_string();
+        app.send_message();
+        let Some(Outgoing::Broadcast { message_id, .. }) = outgoing.recv().await else {
+            panic!("message not sent");
+        };
+
+        // The ack is handled on one tick, the report only on the next
+        events.send(ChatEvent::new(bob.clone(), Message::message_ack(message_id, bob.id))).unwrap();
+        app.handle_events().await;
+        reports.send(SendReport { message_id, recipients: vec![bob.id], error: None }).unwrap();
+        app.handle_events().await;
+
+        assert_eq!(app.messages.last().unwrap().delivery_status(), Some(DeliveryStatus::Delivered));
+    }
+
+    #[tokio::test]
+    async fn discovered_and_connected_peer_is_listed_once() {
+        let Harness { mut app, events, .. } = harness();
+        let us = Arc::new(Identity::ephemeral());
+        let bob = Identity::ephemeral();
+        let mut handler = MessageHandler::new("me".to_string(), us.clone(), events.clone(), 8000, None);
+        let bob_at = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 20)), 7878);
+
+        // Our own announcement echoing back, then bob's
+        let ours = Message::discovery("me".to_string(), 8000, us.peer_id(), us.public_key(), None);
+        handler.handle_message(ours, SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10)), 7878)).unwrap();
+        let discovery = Message::discovery("bob".to_string(), 8001, bob.peer_id(), bob.public_key(), None);
+        handler.handle_message(discovery, bob_at).unwrap();
+
+        // Then the TCP session, which only knows bob's ephemeral port
+        let session_peer = Peer {
+            id: bob.peer_id(),
+            ..Peer::new("bob".to_string(), bob_at.ip(), 53124)
+        };
+        let join = Message::user_join("bob".to_string(), bob.peer_id(), bob.public_key(), None);
+        events.send(ChatEvent::new(session_peer, join)).unwrap();
+        app.handle_events().await;
+
+        assert_eq!(app.peers.len(), 1);
+        assert!(app.peers.contains_key(&bob.peer_id()));
+    }
+
+    #[tokio::test]
+    async fn failed_connect_is_reported_on_the_status_line() {
+        let Harness { mut app, .. } = harness();
+        let (connection_sender, mut requests) = mpsc::unbounded_channel();
+        app.connection_sender = Some(connection_sender);
+
+        app.connect_to("10.0.0.9:8000");
+        let Some(ConnectionRequest::Dial(target, reply)) = requests.recv().await else {
+            panic!("expected a dial request");
+        };
+        assert_eq!(target, "10.0.0.9:8000");
+        reply.send(Err("nobody answered after 3 attempts".to_string())).unwrap();
+
+        app.handle_events().await;
+        assert_eq!(app.status, "Couldn't reach 10.0.0.9:8000: nobody answered after 3 attempts");
+        assert!(app.peers.is_empty());
+    }
+}
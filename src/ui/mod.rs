@@ -1,5 +1,5 @@
 pub mod app;
 pub mod terminal;
 
-pub use app::App;
+pub use app::{App, ConnectionRequest, Outgoing, SendReport};
 pub use terminal::TerminalUI;
@@ -1,3 +1,4 @@
+use crate::ui::app::{ChatMessage, DeliveryStatus};
 use crate::ui::App;
 use anyhow::Result;
 use crossterm::{
@@ -7,7 +8,8 @@ use crossterm::{
     style::{Color, Print, ResetColor, SetForegroundColor},
     terminal::{self, ClearType},
 };
-use std::io::{self, stdout, Write};
+use std::io::{stdout, Write};
+use tokio::sync::watch;
 use tokio::time::{sleep, Duration};
 
 pub struct TerminalUI {
@@ -19,10 +21,28 @@ impl TerminalUI {
         Self { app }
     }
 
-    pub async fn run_interactive(&mut self) -> Result<()> {
+    /// Runs until the user quits or `shutdown` fires. The terminal is put
+    /// back into its normal mode either way.
+    pub async fn run_interactive(&mut self, shutdown: watch::Receiver<bool>) -> Result<()> {
         // Enable raw mode for real-time input
         terminal::enable_raw_mode()?;
 
+        let result = self.input_loop(shutdown).await;
+
+        // Restore terminal
+        terminal::disable_raw_mode()?;
+        execute!(
+            stdout(),
+            cursor::Show,
+            cursor::MoveTo(0, terminal::size()?.1),
+            Print("\n")
+        )?;
+        println!("Goodbye! 👋");
+
+        result
+    }
+
+    async fn input_loop(&mut self, shutdown: watch::Receiver<bool>) -> Result<()> {
         // Hide cursor and clear screen
         execute!(
             stdout(),
@@ -36,7 +56,7 @@ impl TerminalUI {
         let mut last_ui_update = std::time::Instant::now();
 
         // Main input loop
-        loop {
+        while !self.app.should_quit && !*shutdown.borrow() {
             // Handle any pending chat events
             self.app.handle_events().await;
 
@@ -65,16 +85,6 @@ impl TerminalUI {
             sleep(Duration::from_millis(50)).await;
         }
 
-        // Restore terminal
-        terminal::disable_raw_mode()?;
-        execute!(
-            stdout(),
-            cursor::Show,
-            cursor::MoveTo(0, terminal::size()?.1),
-            Print("\n")
-        )?;
-        println!("Goodbye! 👋");
-
         Ok(())
     }
 
@@ -94,7 +104,7 @@ impl TerminalUI {
                 "Channel: {}\n",
                 self.app.channel.clone().unwrap_or_else(|| "(none)".into())
             )),
-            Print("Press Ctrl+C to quit | Type your message and press Enter to send\n"),
+            Print("Type /quit or press Ctrl+C to quit | /msg <user> <text> for a private message | /connect <host:port>\n"),
             Print("─".repeat(separator_width)),
             Print("\n")
         )?;
@@ -147,87 +157,46 @@ impl TerminalUI {
             execute!(stdout(), cursor::MoveToColumn(0), Print("\n"))?;
         }
 
-        // Calculate available space for messages (leaving space for input area)
-        let max_message_lines = if height > 10 { height - 10 } else { 5 };
-
-        // Display recent messages (limited by screen space)
-        let recent_messages: Vec<_> = self
-            .app
-            .messages
-            .iter()
-            .rev()
-            .take(max_message_lines as usize)
-            .rev()
-            .collect();
-
-        if !recent_messages.is_empty() {
+        // Status line; security alerts take over in red until dismissed with Esc
+        if let Some(alert) = &self.app.alert {
             execute!(
                 stdout(),
-                SetForegroundColor(Color::Cyan),
-                Print("💬 Recent Messages:\n"),
+                cursor::MoveToColumn(0),
+                SetForegroundColor(Color::Red),
+                Print(format!("⚠️  {}\n", alert)),
                 ResetColor
             )?;
+        } else {
+            execute!(
+                stdout(),
+                cursor::MoveToColumn(0),
+                SetForegroundColor(Color::DarkGrey),
+                Print(format!("{}\n", self.app.status)),
+                ResetColor
+            )?;
+        }
 
-            for msg in recent_messages {
-                let time = msg.timestamp.format("%H:%M:%S");
-
-                // Calculate max content width considering indentation
-                let indent = "  "; // 2-space indentation for all messages
-
-                // Use safe width calculation with minimum guarantees
-                let safe_width = if width < 40 { 40 } else { width } as usize;
-                let reserved_space = 25; // Reserve space for timestamp, username, etc.
-                let max_content_width = safe_width.saturating_sub(reserved_space);
-
-                // Ensure minimum content width
-                let max_content_width = if max_content_width < 10 {
-                    10
-                } else {
-                    max_content_width
-                };
-
-                let truncated_content = if msg.content.len() > max_content_width {
-                    if max_content_width > 3 {
-                        format!("{}...", &msg.content[..max_content_width - 3])
-                    } else {
-                        "...".to_string()
-                    }
-                } else {
-                    msg.content.clone()
-                };
+        // Calculate available space for messages (leaving space for input area)
+        let max_message_lines = if height > 10 { height - 10 } else { 5 };
 
-                // Always start from beginning of line and use fixed positioning
-                if msg.is_own_message {
-                    execute!(
-                        stdout(),
-                        cursor::MoveToColumn(0),
-                        SetForegroundColor(Color::Blue),
-                        Print(format!("{}[{}] You: {}", indent, time, truncated_content)),
-                        ResetColor,
-                        Print("\n")
-                    )?;
-                } else {
-                    let sender_truncated = if msg.sender.len() > 10 {
-                        format!("{}...", &msg.sender[..7])
-                    } else {
-                        msg.sender.clone()
-                    };
-                    execute!(
-                        stdout(),
-                        cursor::MoveToColumn(0),
-                        SetForegroundColor(Color::Magenta),
-                        Print(format!(
-                            "{}[{}] {}: {}",
-                            indent, time, sender_truncated, truncated_content
-                        )),
-                        ResetColor,
-                        Print("\n")
-                    )?;
-                }
-            }
-            // Add spacing after messages
-            execute!(stdout(), cursor::MoveToColumn(0), Print("\n"))?;
-        }
+        // Private conversations get their own section above the group chat
+        let private_lines = if self.app.private_messages.is_empty() {
+            0
+        } else {
+            (max_message_lines / 3).max(1)
+        };
+        self.print_messages(
+            "🔒 Private Messages:",
+            &self.app.private_messages,
+            private_lines,
+            width,
+        )?;
+        self.print_messages(
+            "💬 Recent Messages:",
+            &self.app.messages,
+            max_message_lines - private_lines,
+            width,
+        )?;
 
         // Display input line with proper sizing
         let safe_width = if width < 20 { 20 } else { width } as usize;
@@ -263,6 +232,132 @@ impl TerminalUI {
         Ok(())
     }
 
+    // Prints the last `max_lines` of `messages` under `title`; nothing at all
+    // if there are none.
+    fn print_messages(
+        &self,
+        title: &str,
+        messages: &[ChatMessage],
+        max_lines: u16,
+        width: u16,
+    ) -> Result<()> {
+        // Display recent messages (limited by screen space)
+        let recent_messages: Vec<_> = messages
+            .iter()
+            .rev()
+            .take(max_lines as usize)
+            .rev()
+            .collect();
+
+        if recent_messages.is_empty() {
+            return Ok(());
+        }
+
+        execute!(
+            stdout(),
+            SetForegroundColor(Color::Cyan),
+            Print(format!("{}\n", title)),
+            ResetColor
+        )?;
+
+        for msg in recent_messages {
+            let time = msg.timestamp.format("%H:%M:%S");
+
+            // Calculate max content width considering indentation
+            let indent = "  "; // 2-space indentation for all messages
+
+            // Use safe width calculation with minimum guarantees
+            let safe_width = if width < 40 { 40 } else { width } as usize;
+            let reserved_space = 25; // Reserve space for timestamp, username, etc.
+            let max_content_width = safe_width.saturating_sub(reserved_space);
+
+            // Ensure minimum content width
+            let max_content_width = if max_content_width < 10 {
+                10
+            } else {
+                max_content_width
+            };
+
+            let truncated_content = if msg.content.len() > max_content_width {
+                if max_content_width > 3 {
+                    format!("{}...", &msg.content[..max_content_width - 3])
+                } else {
+                    "...".to_string()
+                }
+            } else {
+                msg.content.clone()
+            };
+
+            // Always start from beginning of line and use fixed positioning
+            if msg.is_own_message {
+                let you = match &msg.recipient {
+                    Some(recipient) => format!("You → {}", recipient),
+                    None => "You".to_string(),
+                };
+                // … waiting for receipts, ✓✓ everyone got it, ✓ n/m only
+                // some did, ✗ nobody confirmed it
+                let marker = match msg.delivery_status() {
+                    Some(DeliveryStatus::Pending) | None => " …".to_string(),
+                    Some(DeliveryStatus::Delivered) => " ✓✓".to_string(),
+                    Some(DeliveryStatus::Partial { delivered, total }) => {
+                        format!(" ✓ {}/{}", delivered, total)
+                    }
+                    Some(DeliveryStatus::Failed) => " ✗".to_string(),
+                };
+                execute!(
+                    stdout(),
+                    cursor::MoveToColumn(0),
+                    SetForegroundColor(Color::Blue),
+                    Print(format!(
+                        "{}[{}] {}: {}{}",
+                        indent, time, you, truncated_content, marker
+                    )),
+                    ResetColor,
+                    Print("\n")
+                )?;
+            } else {
+                let sender_truncated = if msg.sender.len() > 10 {
+                    format!("{}...", &msg.sender[..7])
+                } else {
+                    msg.sender.clone()
+                };
+                // ✓ = signed by the sender's identity key; unsigned
+                // messages (older peers) are flagged in yellow
+                let (badge, mut color) = if msg.verified {
+                    (" ✓", Color::Magenta)
+                } else {
+                    (" (unverified)", Color::Yellow)
+                };
+                // Messages from before we joined are dimmed and marked ↺
+                let time = if msg.backfilled {
+                    color = Color::DarkGrey;
+                    format!("↺ {}", time)
+                } else {
+                    time.to_string()
+                };
+                // Messages that reached us through another peer name it
+                let via = match &msg.relayed_by {
+                    Some(relay) => format!(" via {}", relay),
+                    None => String::new(),
+                };
+                execute!(
+                    stdout(),
+                    cursor::MoveToColumn(0),
+                    SetForegroundColor(color),
+                    Print(format!(
+                        "{}[{}] {}{}{}: {}",
+                        indent, time, sender_truncated, badge, via, truncated_content
+                    )),
+                    ResetColor,
+                    Print("\n")
+                )?;
+            }
+        }
+        // Add spacing after messages
+        execute!(stdout(), cursor::MoveToColumn(0), Print("\n"))?;
+        Ok(())
+    }
+
     async fn handle_key_event(&mut self, key_event: KeyEvent) -> Result<bool> {
         // Only handle key press events (not release)
         if key_event.kind != KeyEventKind::Press {
@@ -278,6 +373,11 @@ impl TerminalUI {
                 // Ctrl+C to quit
                 return Ok(true);
             }
+            KeyCode::Enter if self.app.input.trim() == "/quit" => {
+                self.app.input.clear();
+                self.app.quit();
+                return Ok(true);
+            }
             KeyCode::Enter => {
                 // Send message
                 if !self.app.input.trim().is_empty() {
@@ -285,6 +385,10 @@ impl TerminalUI {
                     self.app.input.clear();
                 }
             }
+            KeyCode::Esc => {
+                // Dismiss the current alert
+                self.app.alert = None;
+            }
             KeyCode::Backspace => {
                 // Remove last character
                 self.app.remove_char();
@@ -298,183 +402,4 @@ impl TerminalUI {
 
         Ok(false)
     }
-
-    pub async fn run(&mut self) -> Result<()> {
-        println!("🚀 Local Chat v1.0.0");
-        println!("Connected as: {}", self.app.username);
-        println!("Discovering peers on local network...");
-        println!();
-        println!("Commands:");
-        println!("  Type a message and press Enter to send");
-        println!("  Type 'quit' or 'exit' to leave");
-        println!("  Ctrl+C to force quit");
-        println!("{}", "=".repeat(50));
-
-        self.app.update_status("Searching for peers...".to_string());
-
-        loop {
-            // Handle any pending events
-            self.app.handle_events().await;
-
-            // Display current status
-            self.display_status();
-
-            // Check if we should quit
-            if self.app.should_quit {
-                break;
-            }
-
-            // Check for user input (non-blocking)
-            if let Some(input) = self.read_input_non_blocking()? {
-                self.handle_input(input);
-            }
-
-            // Small delay to prevent busy waiting
-            sleep(Duration::from_millis(100)).await;
-        }
-
-        println!("\nGoodbye! 👋");
-        Ok(())
-    }
-
-    fn display_status(&self) {
-        // Clear the current line and move cursor to beginning
-        print!("\r{}", " ".repeat(80));
-        print!("\r");
-
-        // Display peer count and status
-        let peer_count = self.app.get_peer_count();
-        if peer_count > 0 {
-            print!("🟢 Online ({}) | {}", peer_count, self.app.status);
-        } else {
-            print!("🔍 Searching... | {}", self.app.status);
-        }
-
-        // Display recent messages (last 3)
-        if !self.app.messages.is_empty() {
-            println!();
-            let recent_messages: Vec<_> = self.app.messages.iter().rev().take(3).rev().collect();
-
-            for msg in recent_messages {
-                let time = msg.timestamp.format("%H:%M");
-                if msg.is_own_message {
-                    println!("[{}] You: {}", time, msg.content);
-                } else {
-                    println!("[{}] {}: {}", time, msg.sender, msg.content);
-                }
-            }
-        }
-
-        print!("> {}", self.app.input);
-        io::Write::flush(&mut stdout()).unwrap();
-    }
-
-    fn read_input_non_blocking(&self) -> Result<Option<String>> {
-        // This is a simplified implementation
-        // In a real application, you'd want to use a proper async input handling
-        // For now, we'll simulate input checking
-        Ok(None)
-    }
-
-    fn handle_input(&mut self, input: String) {
-        let input = input.trim();
-
-        if input.is_empty() {
-            return;
-        }
-
-        match input.to_lowercase().as_str() {
-            "quit" | "exit" => {
-                self.app.quit();
-            }
-            "help" => {
-                println!();
-                println!("Available commands:");
-                println!("  help - Show this help message");
-                println!("  peers - List connected peers");
-                println!("  quit/exit - Leave the chat");
-                println!();
-            }
-            "peers" => {
-                println!();
-                let peers = self.app.get_peer_list();
-                if peers.is_empty() {
-                    println!("No peers connected.");
-                } else {
-                    println!("Connected peers:");
-                    for peer in peers {
-                        println!("  - {}", peer);
-                    }
-                }
-                println!();
-            }
-            _ => {
-                // Treat as a message to send
-                self.app.input = input.to_string();
-                self.app.send_message();
-            }
-        }
-    }
-}
-
-// Simplified terminal input handling for demo
-// In production, you'd want to use crossterm or similar for proper async terminal handling
-impl TerminalUI {
-    pub async fn run_simple(&mut self) -> Result<()> {
-        println!("🚀 Local Chat v1.0.0");
-        println!("Connected as: {}", self.app.username);
-        println!("Discovering peers on local network...");
-        println!("Listening on UDP port 7878 for peer discovery...");
-        println!();
-
-        let mut last_peer_count = 0;
-        let mut status_counter = 0;
-
-        // Enhanced message loop for peer discovery
-        loop {
-            self.app.handle_events().await;
-
-            let peer_count = self.app.get_peer_count();
-
-            // Show status every 10 iterations or when peer count changes
-            if status_counter % 10 == 0 || peer_count != last_peer_count {
-                println!(
-                    "Status: {} peers discovered | {}",
-                    peer_count, self.app.status
-                );
-
-                if peer_count > 0 {
-                    println!("🟢 Discovered peers:");
-                    for peer_info in self.app.get_peer_list() {
-                        println!("  - {}", peer_info);
-                    }
-                    println!();
-                }
-
-                last_peer_count = peer_count;
-            }
-
-            // Show any new messages
-            if !self.app.messages.is_empty() {
-                let last_msg = self.app.messages.last().unwrap();
-                let time = last_msg.timestamp.format("%H:%M");
-                if last_msg.is_own_message {
-                    println!("[{}] You: {}", time, last_msg.content);
-                } else {
-                    println!("[{}] {}: {}", time, last_msg.sender, last_msg.content);
-                }
-            }
-
-            sleep(Duration::from_millis(500)).await;
-            status_counter += 1;
-
-            // Run for 2 minutes to see discovery in action
-            if status_counter > 240 {
-                println!("Demo completed. Discovered {} peers total.", peer_count);
-                break;
-            }
-        }
-
-        Ok(())
-    }
 }